@@ -0,0 +1,93 @@
+use libc::{c_long, c_ulong};
+
+use crate::{fail, UfoErrorCode};
+
+/// What this library was built with and what the running kernel allows
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UfoCapabilities {
+    pub version_major: u32,
+    pub version_minor: u32,
+    pub version_patch: u32,
+    /// userfaultfd can be opened by this process, without it no UFO can be created
+    pub uffd_available: bool,
+    /// the kernel reports write-protect faults
+    pub uffd_write_protect: bool,
+    /// the kernel reports minor faults on shmem or hugetlbfs
+    pub uffd_minor_faults: bool,
+    /// not built into this library
+    pub hugepages: bool,
+    /// not built into this library
+    pub compression: bool,
+}
+
+/// struct uffdio_api from linux/userfaultfd.h
+#[repr(C)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+const UFFD_API: u64 = 0xaa;
+/// _IOWR(UFFDIO, _UFFDIO_API, struct uffdio_api)
+const UFFDIO_API: c_ulong = 0xc018_aa3f;
+const UFFD_FEATURE_PAGEFAULT_FLAG_WP: u64 = 1 << 0;
+const UFFD_FEATURE_MINOR_HUGETLBFS: u64 = 1 << 9;
+const UFFD_FEATURE_MINOR_SHMEM: u64 = 1 << 10;
+
+/// The userfaultfd features the kernel offers, None if it can't be opened
+pub(crate) fn probe_uffd_features() -> Option<u64> {
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_userfaultfd,
+            (libc::O_CLOEXEC | libc::O_NONBLOCK) as c_long,
+        )
+    };
+    if fd < 0 {
+        return None;
+    }
+    let fd = fd as libc::c_int;
+
+    // asking for no features makes the kernel report every one it has
+    let mut api = UffdioApi {
+        api: UFFD_API,
+        features: 0,
+        ioctls: 0,
+    };
+    let ret = unsafe { libc::ioctl(fd, UFFDIO_API, &mut api) };
+    unsafe { libc::close(fd) };
+    (ret == 0).then_some(api.features)
+}
+
+fn version_part(part: &str) -> u32 {
+    part.parse().unwrap_or(0)
+}
+
+/// Fill `out` with this library's version and the features it can use here.
+/// Kernel support is probed each call, so call it once and keep the result.
+///
+/// # Safety
+///
+/// `out` must be NULL or valid for writing a UfoCapabilities.
+#[no_mangle]
+pub unsafe extern "C" fn ufo_capabilities(out: *mut UfoCapabilities) -> UfoErrorCode {
+    if out.is_null() {
+        return fail(UfoErrorCode::NullHandle, "null capabilities pointer");
+    }
+
+    let features = probe_uffd_features();
+    let has = |feature| features.is_some_and(|f| f & feature != 0);
+    let capabilities = UfoCapabilities {
+        version_major: version_part(env!("CARGO_PKG_VERSION_MAJOR")),
+        version_minor: version_part(env!("CARGO_PKG_VERSION_MINOR")),
+        version_patch: version_part(env!("CARGO_PKG_VERSION_PATCH")),
+        uffd_available: features.is_some(),
+        uffd_write_protect: has(UFFD_FEATURE_PAGEFAULT_FLAG_WP),
+        uffd_minor_faults: has(UFFD_FEATURE_MINOR_HUGETLBFS | UFFD_FEATURE_MINOR_SHMEM),
+        hugepages: false,
+        compression: false,
+    };
+    *out = capabilities;
+    UfoErrorCode::Ok
+}
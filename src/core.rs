@@ -1,12 +1,24 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock},
+    convert::TryFrom,
+    ffi::CString,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, Once, RwLock, Weak,
+    },
 };
 
+use crossbeam::channel;
 use libc::c_void;
-use ufo_core::{UfoCoreConfig, UfoId, UfoObjectParams, UfoPopulateError, UfoWritebackListenerFn};
+use ufo_core::{
+    UfoCoreConfig, UfoId, UfoObject, UfoObjectParams, UfoPopulateError, UfoWritebackListenerFn,
+    WrappedUfoObject,
+};
 use ufo_core::sizes::*;
 
+use crate::lint::{disk_space, lint_config, ConfigWarning, LintInput, LintObject};
+use crate::populate::{PopulateCounters, PopulateGuard, Populator};
 use crate::UfoPopulateData;
 
 use super::*;
@@ -14,19 +26,309 @@ use super::*;
 pub type UfoEventCallbackData = *mut libc::c_void;
 pub type UfoEventCallback = extern "C" fn(UfoEventCallbackData, &UfoEventandTimestamp);
 
+pub type UfoForEachData = *mut libc::c_void;
+/// Called with the id, body pointer, and element count of each live UFO.
+/// Returning non-zero stops the iteration.
+pub type UfoForEachCallback = extern "C" fn(UfoForEachData, u64, usize, usize) -> i32;
+
 /// Rust uses closures, but C uses callback functions and data pointers
 /// store these parameters ourselves
 pub(crate) struct CParams {
     pub(crate) populate_data: UfoPopulateData,
-    pub(crate) populate_fn: UfoPopulateCallout,
+    pub(crate) populate_fn: Option<UfoPopulateCallout>,
 
     pub(crate) writeback_listener_data: UfoWritebackListenerData,
     pub(crate) writeback_listener: UfoWritebackListener,
+
+    pub(crate) ufo: Weak<RwLock<UfoObject>>,
+    pub(crate) stats: Arc<PopulateCounters>,
+    /// resets whose saved header has not been written back yet
+    pub(crate) header_restores: Arc<AtomicUsize>,
+}
+
+// The pointers are only handed back to C, never dereferenced here, and the C
+// side already has to accept them being used from the populate thread
+unsafe impl Send for CParams {}
+unsafe impl Sync for CParams {}
+
+/// Calls the populate data finalizer when dropped
+pub(crate) struct FinalizeOnDrop {
+    data: usize,
+    finalizer: extern "C" fn(UfoPopulateData),
+}
+
+impl Drop for FinalizeOnDrop {
+    fn drop(&mut self) {
+        (self.finalizer)(self.data as *mut c_void);
+    }
+}
+
+/// How many async resets and frees have been started and how many of those
+/// have finished, so ufo_core_drain can wait without keeping finished ones
+#[derive(Default)]
+pub(crate) struct PendingOps {
+    /// (started, finished)
+    counts: Mutex<(u64, u64)>,
+    finished: Condvar,
+}
+
+impl PendingOps {
+    fn start(&self) {
+        self.counts.lock().expect("can't lock pending").0 += 1;
+    }
+
+    fn finish(&self) {
+        self.counts.lock().expect("can't lock pending").1 += 1;
+        self.finished.notify_all();
+    }
+
+    /// The core's reaper thread, which finishes each operation in the order
+    /// they started and exits once the core drops its sender
+    fn reap(&self, ops: channel::Receiver<Deferred>) {
+        for op in ops {
+            op.finish();
+            self.finish();
+        }
+    }
+
+    /// Wait for every operation started before the call. False on timeout.
+    fn drain(&self, timeout: Option<std::time::Duration>) -> bool {
+        let counts = self.counts.lock().expect("can't lock pending");
+        let started = counts.0;
+        let unfinished = |counts: &mut (u64, u64)| counts.1 < started;
+        match timeout {
+            None => {
+                let _drained = self
+                    .finished
+                    .wait_while(counts, unfinished)
+                    .expect("can't lock pending");
+                true
+            }
+            Some(timeout) => {
+                let (_counts, waited) = self
+                    .finished
+                    .wait_timeout_while(counts, timeout, unfinished)
+                    .expect("can't lock pending");
+                !waited.timed_out()
+            }
+        }
+    }
+}
+
+/// Settings for ufo_new_core_with_params. Fields added in later versions are
+/// carved out of `reserved`, and zero always selects the default, so zero the
+/// whole struct before filling it in.
+#[repr(C)]
+pub struct UfoCoreParams {
+    pub writeback_temp_path: *const libc::c_char,
+    pub low_watermark: usize,
+    pub high_watermark: usize,
+    /// 0 for the default. The core currently runs one populate thread.
+    pub populate_threads: u32,
+    /// UFO_CORE_FLAG_* bits
+    pub flags: u64,
+    /// Largest header plus body a single UFO may have, 0 for no limit
+    pub max_object_bytes: u64,
+    pub reserved: [u64; 4],
+}
+
+/// Populate through a buffer with canary bytes after it and fail any populate
+/// that writes past its chunk. Costs a copy per populate, so debug only.
+pub const UFO_CORE_FLAG_POPULATE_CANARIES: u64 = 1;
+
+/// Totals since the core was created, as reported by ufo_core_lifetime_stats
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UfoCoreLifetimeStats {
+    pub ufos_created: u64,
+    pub ufos_freed: u64,
+    /// summed over every UFO the core has allocated, freed or not
+    pub populate: UfoObjStats,
+}
+
+/// The settings a core was created with, as reported by ufo_core_config
+#[repr(C)]
+pub struct UfoCoreConfigC {
+    pub low_watermark: usize,
+    pub high_watermark: usize,
+    /// Borrowed from the core. NUL terminated and valid until the core is shut down.
+    pub writeback_temp_path: *const libc::c_char,
+    pub writeback_temp_path_len: usize,
+}
+
+/// Bumped in every forked child, so cores can spot a fork without a getpid
+/// call on every access
+static FORK_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+unsafe extern "C" fn bump_fork_generation() {
+    FORK_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The current FORK_GENERATION, installing the fork handler on first use
+fn fork_generation() -> u64 {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| unsafe {
+        libc::pthread_atfork(None, None, Some(bump_fork_generation));
+    });
+    FORK_GENERATION.load(Ordering::Relaxed)
 }
 
 pub(crate) struct UfoCCore {
     pub(crate) the_core: Arc<ufo_core::UfoCore>,
     pub(crate) data_map: RwLock<HashMap<UfoId, CParams>>,
+
+    pub(crate) writeback_temp_path: CString,
+    pub(crate) low_watermark: usize,
+    pub(crate) high_watermark: usize,
+
+    pub(crate) config_warnings: RwLock<Vec<ConfigWarning>>,
+    pub(crate) strict_lints: AtomicBool,
+    pub(crate) populate_canaries: bool,
+    pub(crate) max_object_bytes: Option<usize>,
+    pub(crate) shut_down: AtomicBool,
+    /// FORK_GENERATION when the core was created, the core's threads only
+    /// exist in that process
+    pub(crate) fork_generation: u64,
+
+    pub(crate) ufos_created: AtomicU64,
+    pub(crate) ufos_freed: AtomicU64,
+    /// populate totals across every UFO this core has allocated
+    pub(crate) populate_totals: Arc<PopulateCounters>,
+
+    /// resets and frees started by the async calls, for the reaper thread
+    pub(crate) pending: channel::Sender<Deferred>,
+    pub(crate) pending_ops: Arc<PendingOps>,
+
+    /// queue for the hint thread, which exits when this is dropped
+    pub(crate) hints: channel::Sender<Hint>,
+    /// UFOs whose bodies are being touched with their lock released, once per Touching
+    pub(crate) touching: Mutex<Vec<UfoId>>,
+    pub(crate) touch_done: Condvar,
+}
+
+/// Marks a UFO as being touched with its lock released, by the hint thread or
+/// a header restore. start_free and shutdown wait for it to be dropped rather
+/// than unmap the body underneath.
+pub(crate) struct Touching {
+    core: Arc<UfoCCore>,
+    id: UfoId,
+}
+
+impl Drop for Touching {
+    fn drop(&mut self) {
+        let mut touching = self.core.touching.lock().expect("can't lock touching");
+        if let Some(i) = touching.iter().position(|id| *id == self.id) {
+            touching.swap_remove(i);
+        }
+        self.core.touch_done.notify_all();
+    }
+}
+
+impl UfoCCore {
+    pub(crate) fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::Acquire)
+    }
+
+    fn lifetime_stats(&self) -> UfoCoreLifetimeStats {
+        UfoCoreLifetimeStats {
+            ufos_created: self.ufos_created.load(Ordering::Relaxed),
+            ufos_freed: self.ufos_freed.load(Ordering::Relaxed),
+            populate: self.populate_totals.snapshot(),
+        }
+    }
+
+    /// True in a child forked after the core was created. The core's threads
+    /// only exist in the parent, so anything that waits on them would hang.
+    pub(crate) fn is_forked(&self) -> bool {
+        FORK_GENERATION.load(Ordering::Relaxed) != self.fork_generation
+    }
+
+    /// Hand an async operation to the reaper thread so ufo_core_drain can wait for it
+    pub(crate) fn defer(&self, op: Deferred) {
+        self.pending_ops.start();
+        if let Err(unsent) = self.pending.send(op) {
+            unsent.into_inner().finish();
+            self.pending_ops.finish();
+        }
+    }
+
+    /// Mark the UFO with this id as touched until the result is dropped, or
+    /// None once shutdown has started. Call with the UFO locked and live, so
+    /// no free can slip in between the check and the mark.
+    pub(crate) fn touch(self: &Arc<Self>, id: UfoId) -> Option<Touching> {
+        let mut touching = self.touching.lock().expect("can't lock touching");
+        // shutdown sets the flag before it waits on touching
+        if self.is_shut_down() {
+            return None;
+        }
+        touching.push(id);
+        Some(Touching {
+            core: self.clone(),
+            id,
+        })
+    }
+
+    /// Block while a UFO whose id matches is being touched
+    pub(crate) fn wait_for_touches<F: Fn(UfoId) -> bool>(&self, matches: F) {
+        let touching = self.touching.lock().expect("can't lock touching");
+        let _idle = self
+            .touch_done
+            .wait_while(touching, |touching| touching.iter().any(|id| matches(*id)))
+            .expect("can't lock touching");
+    }
+
+    /// False once a handle has freed the UFO with this id
+    pub(crate) fn owns(&self, id: UfoId) -> bool {
+        self.data_map
+            .read()
+            .expect("can't lock map")
+            .contains_key(&id)
+    }
+
+    /// The UFO with this id, provided the core has not freed it
+    pub(crate) fn live_ufo_by_id(&self, id: UfoId) -> Option<WrappedUfoObject> {
+        let ufo = self
+            .data_map
+            .read()
+            .expect("can't lock map")
+            .get(&id)?
+            .ufo
+            .upgrade()?;
+        // handles keep freed objects alive, so ask the core whether it still owns the body
+        let body = ufo.read().ok()?.body_ptr() as usize;
+        let current = self.the_core.get_ufo_by_address(body).ok()?;
+        Some(ufo).filter(|ufo| Arc::ptr_eq(ufo, &current))
+    }
+
+    fn lint(&self, object: Option<LintObject>) -> Vec<ConfigWarning> {
+        let writeback_temp_path = self
+            .writeback_temp_path
+            .to_str()
+            .expect("path was UTF-8 when the core was created");
+        let warnings = lint_config(&LintInput {
+            low_watermark: self.low_watermark,
+            high_watermark: self.high_watermark,
+            writeback_temp_path,
+            // only the core on its own is checked against the disk
+            disk_space: object
+                .is_none()
+                .then(|| disk_space(writeback_temp_path))
+                .flatten(),
+            object,
+        });
+
+        let mut known = self.config_warnings.write().expect("can't lock warnings");
+        for w in &warnings {
+            // one entry per code, so this stays as short as the rule table
+            if known.iter().any(|k| k.code == w.code) {
+                log::debug!("{}", w);
+            } else {
+                log::warn!("{}", w);
+                known.push(w.clone());
+            }
+        }
+        warnings
+    }
 }
 
 #[repr(C)]
@@ -42,45 +344,253 @@ impl UfoCore {
         low_water_mark: usize,
         high_water_mark: usize,
     ) -> Self {
-        std::panic::catch_unwind(|| {
-            let wb = std::ffi::CStr::from_ptr(writeback_temp_path)
-                .to_str()
-                .expect("invalid string")
-                .to_string();
+        let mut low_water_mark = low_water_mark;
+        let mut high_water_mark = high_water_mark;
+
+        if low_water_mark > high_water_mark {
+            std::mem::swap(&mut low_water_mark, &mut high_water_mark);
+        }
 
-            let mut low_water_mark = low_water_mark;
-            let mut high_water_mark = high_water_mark;
+        Self::ufo_new_core_with_params(&UfoCoreParams {
+            writeback_temp_path,
+            low_watermark: low_water_mark,
+            high_watermark: high_water_mark,
+            populate_threads: 0,
+            flags: 0,
+            max_object_bytes: 0,
+            reserved: [0; 4],
+        })
+    }
 
-            if low_water_mark > high_water_mark {
-                std::mem::swap(&mut low_water_mark, &mut high_water_mark);
+    /// Create a core from `params`, see UfoCoreParams
+    ///
+    /// # Safety
+    ///
+    /// `params` must be NULL or point to a UfoCoreParams whose
+    /// `writeback_temp_path` is NULL or a NUL terminated string.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_new_core_with_params(params: *const UfoCoreParams) -> Self {
+        catch_error(|| {
+            let params = match params.as_ref() {
+                Some(params) => params,
+                None => {
+                    set_last_error("null core parameters");
+                    return Self::none();
+                }
+            };
+            if params.writeback_temp_path.is_null() {
+                set_last_error("null writeback_temp_path");
+                return Self::none();
+            }
+            if params.low_watermark >= params.high_watermark {
+                set_last_error(format!(
+                    "low watermark {} must be below high watermark {}",
+                    params.low_watermark, params.high_watermark
+                ));
+                return Self::none();
+            }
+            if params.populate_threads > 1 {
+                log::warn!(
+                    "populate_threads = {} requested, the core runs a single populate thread",
+                    params.populate_threads
+                );
+            }
+
+            let wb = match std::ffi::CStr::from_ptr(params.writeback_temp_path).to_str() {
+                Ok(wb) => wb.to_string(),
+                Err(e) => {
+                    set_last_error(format!("writeback_temp_path is not UTF-8: {}", e));
+                    return Self::none();
+                }
+            };
+
+            // the core unwraps userfaultfd creation, so check first rather than panic in there
+            if crate::capabilities::probe_uffd_features().is_none() {
+                set_last_error(format!(
+                    "userfaultfd is unavailable: {}",
+                    std::io::Error::last_os_error()
+                ));
+                return Self::none();
             }
-            assert!(low_water_mark < high_water_mark);
 
             let config = UfoCoreConfig {
-                writeback_temp_path: wb,
-                low_watermark: low_water_mark,
-                high_watermark: high_water_mark,
+                writeback_temp_path: wb.clone(),
+                low_watermark: params.low_watermark,
+                high_watermark: params.high_watermark,
             };
 
+            // at most this many hints wait for the hint thread, later ones are dropped
+            const HINT_QUEUE_LEN: usize = 64;
+            let (hints, hint_recv) = channel::bounded(HINT_QUEUE_LEN);
+            if let Err(e) = std::thread::Builder::new()
+                .name("ufo-hint".to_string())
+                .spawn(move || run_hints(hint_recv))
+            {
+                set_last_error(format!("unable to start the hint thread: {}", e));
+                return Self::none();
+            }
+
+            let pending_ops = Arc::new(PendingOps::default());
+            let reaper_ops = pending_ops.clone();
+            let (pending, waits) = channel::unbounded();
+            if let Err(e) = std::thread::Builder::new()
+                .name("ufo-reaper".to_string())
+                .spawn(move || reaper_ops.reap(waits))
+            {
+                set_last_error(format!("unable to start the reaper thread: {}", e));
+                return Self::none();
+            }
+
             let core = ufo_core::UfoCore::new(config);
             match core {
-                Err(_) => Self::none(),
-                Ok(core) => Self::wrap(Arc::new(UfoCCore {
-                    the_core: core,
-                    data_map: RwLock::new(HashMap::new()),
-                })),
+                Err(e) => {
+                    set_last_error(format!("unable to create UFO core: {:?}", e));
+                    Self::none()
+                }
+                Ok(core) => {
+                    let core = UfoCCore {
+                        the_core: core,
+                        data_map: RwLock::new(HashMap::new()),
+                        writeback_temp_path: CString::new(wb).expect("path came from a C string"),
+                        low_watermark: params.low_watermark,
+                        high_watermark: params.high_watermark,
+                        config_warnings: RwLock::new(Vec::new()),
+                        strict_lints: AtomicBool::new(false),
+                        populate_canaries: params.flags & UFO_CORE_FLAG_POPULATE_CANARIES != 0,
+                        // a cap beyond the address space is no cap at all
+                        max_object_bytes: Some(params.max_object_bytes)
+                            .filter(|cap| *cap > 0)
+                            .and_then(|cap| usize::try_from(cap).ok()),
+                        pending,
+                        pending_ops,
+                        hints,
+                        touching: Mutex::new(Vec::new()),
+                        touch_done: Condvar::new(),
+                        shut_down: AtomicBool::new(false),
+                        fork_generation: fork_generation(),
+                        ufos_created: AtomicU64::new(0),
+                        ufos_freed: AtomicU64::new(0),
+                        populate_totals: Arc::new(PopulateCounters::default()),
+                    };
+                    core.lint(None);
+                    Self::wrap(Arc::new(core))
+                }
             }
         })
         .unwrap_or_else(|_| Self::none())
     }
 
+    /// The core, unless the handle is null or the core has been shut down
+    fn running(&self) -> Result<&Arc<UfoCCore>, UfoErrorCode> {
+        match self.deref() {
+            None => Err(fail(UfoErrorCode::NullHandle, "null UFO core handle")),
+            Some(core) if core.is_forked() => Err(fail(
+                UfoErrorCode::CoreShutdown,
+                "the UFO core belongs to the parent process",
+            )),
+            Some(core) if core.is_shut_down() => Err(fail(
+                UfoErrorCode::CoreShutdown,
+                "the UFO core has been shut down",
+            )),
+            Some(core) => Ok(core),
+        }
+    }
+
+    /// Shut the core down, freeing every UFO it owns. Returns 0 on the first
+    /// call, 1 if the core was already shut down, and a negative UfoErrorCode
+    /// on failure. The handle stays valid until ufo_core_free.
     #[no_mangle]
-    pub extern "C" fn ufo_core_shutdown(self) {
-        std::panic::catch_unwind(|| {
-            if let Some(core) = self.deref() {
+    pub extern "C" fn ufo_core_shutdown(&self) -> i32 {
+        catch_error(|| match self.deref() {
+            None => fail(UfoErrorCode::NullHandle, "null UFO core handle") as i32,
+            Some(core) if core.is_forked() => fail(
+                UfoErrorCode::CoreShutdown,
+                "the UFO core belongs to the parent process",
+            ) as i32,
+            Some(core) if core.shut_down.swap(true, Ordering::AcqRel) => 1,
+            Some(core) => {
+                // nothing starts touching once the flag is set, let running touches finish
+                core.wait_for_touches(|_| true);
                 core.the_core.shutdown();
+                let stats = core.lifetime_stats();
+                log::info!(
+                    "UFO core shut down: {} UFOs created, {} freed, {} populate calls ({} failed) \
+                     filling {} bytes in {}ms",
+                    stats.ufos_created,
+                    stats.ufos_freed,
+                    stats.populate.populate_calls,
+                    stats.populate.populate_failures,
+                    stats.populate.populate_bytes,
+                    stats.populate.populate_nanos / 1_000_000
+                );
+                0
+            }
+        })
+        .unwrap_or(UfoErrorCode::Panic as i32)
+    }
+
+    /// Release the core handle, shutting the core down first if
+    /// ufo_core_shutdown was not called. In a forked child the core is
+    /// leaked instead, since dropping it would talk to the parent's threads.
+    #[no_mangle]
+    pub extern "C" fn ufo_core_free(self) {
+        if self.deref().is_some_and(|core| core.is_forked()) {
+            std::mem::forget(self);
+            return;
+        }
+        self.ufo_core_shutdown();
+    }
+
+    /// Wait for every ufo_reset_async and ufo_free_async issued so far. A
+    /// timeout of 0 waits indefinitely. On Timeout the operations carry on in
+    /// the background, and a later drain waits for them again.
+    #[no_mangle]
+    pub extern "C" fn ufo_core_drain(&self, timeout_ms: u64) -> UfoErrorCode {
+        catch_error(|| {
+            let core = match self.deref() {
+                Some(core) if core.is_forked() => {
+                    return fail(
+                        UfoErrorCode::CoreShutdown,
+                        "the UFO core belongs to the parent process",
+                    )
+                }
+                Some(core) => core,
+                None => return fail(UfoErrorCode::NullHandle, "null UFO core handle"),
+            };
+            let timeout =
+                Some(std::time::Duration::from_millis(timeout_ms)).filter(|_| timeout_ms > 0);
+            if core.pending_ops.drain(timeout) {
+                UfoErrorCode::Ok
+            } else {
+                fail(
+                    UfoErrorCode::Timeout,
+                    format!("pending operations still running after {}ms", timeout_ms),
+                )
             }
-        }).expect("error during shutdown");
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// Copy the core's totals since creation into `out`. Still answers after
+    /// shutdown, so the final numbers can be read back.
+    ///
+    /// # Safety
+    ///
+    /// `out` must be NULL or valid for writing a UfoCoreLifetimeStats.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_core_lifetime_stats(
+        &self,
+        out: *mut UfoCoreLifetimeStats,
+    ) -> UfoErrorCode {
+        catch_error(|| match self.deref() {
+            None => fail(UfoErrorCode::NullHandle, "null UFO core handle"),
+            Some(_) if out.is_null() => fail(UfoErrorCode::NullHandle, "null stats pointer"),
+            Some(core) => {
+                *out = core.lifetime_stats();
+                UfoErrorCode::Ok
+            }
+        })
+        .unwrap_or(UfoErrorCode::Panic)
     }
 
     #[no_mangle]
@@ -88,15 +598,207 @@ impl UfoCore {
         self.deref().is_none()
     }
 
+    /// Copy the core's settings into `out`
+    ///
+    /// # Safety
+    ///
+    /// `out` must be NULL or valid for writing a UfoCoreConfigC.
     #[no_mangle]
-    pub extern "C" fn ufo_get_by_address(&self, ptr: *mut libc::c_void) -> UfoObj {
-        std::panic::catch_unwind(|| {
+    pub unsafe extern "C" fn ufo_core_config(&self, out: *mut UfoCoreConfigC) -> UfoErrorCode {
+        catch_error(|| match self.deref() {
+            None => fail(UfoErrorCode::NullHandle, "null UFO core handle"),
+            Some(_) if out.is_null() => fail(UfoErrorCode::NullHandle, "null config pointer"),
+            Some(core) => {
+                *out = UfoCoreConfigC {
+                    low_watermark: core.low_watermark,
+                    high_watermark: core.high_watermark,
+                    writeback_temp_path: core.writeback_temp_path.as_ptr(),
+                    writeback_temp_path_len: core.writeback_temp_path.as_bytes().len(),
+                };
+                UfoErrorCode::Ok
+            }
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// When strict, allocations that trip a strict lint fail instead of warning
+    #[no_mangle]
+    pub extern "C" fn ufo_core_set_strict_lints(&self, strict: bool) -> bool {
+        self.deref()
+            .map(|core| core.strict_lints.store(strict, Ordering::Release))
+            .is_some()
+    }
+
+    /// Copy the first warning seen for each lint code into `buf`, one per line,
+    /// truncated to fit and NUL terminated. Returns the untruncated length.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be NULL or valid for writing `len` bytes.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_core_config_warnings(
+        &self,
+        buf: *mut libc::c_char,
+        len: usize,
+    ) -> usize {
+        catch_error(|| {
             self.deref()
+                .map(|core| {
+                    let warnings = core.config_warnings.read().expect("can't lock warnings");
+                    let text = warnings
+                        .iter()
+                        .map(|w| w.to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n");
+
+                    if !buf.is_null() && len > 0 {
+                        let n = text.len().min(len - 1);
+                        std::ptr::copy_nonoverlapping(text.as_ptr(), buf.cast(), n);
+                        *buf.add(n) = 0;
+                    }
+                    text.len()
+                })
+                .unwrap_or(0)
+        })
+        .unwrap_or(0)
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ufo_get_by_address(&self, ptr: *mut libc::c_void) -> UfoObj {
+        catch_error(|| {
+            self.running()
+                .ok()
                 .and_then(|core| {
                     core.the_core
                         .get_ufo_by_address(ptr as usize)
+                        .map_err(|e| set_last_error(format!("no UFO at {:p}: {:?}", ptr, e)))
                         .ok() // okay if this fails, we just return "none"
-                        .map(UfoObj::wrap)
+                        .map(|ufo| UfoObj::new(ufo, core))
+                })
+                .unwrap_or_else(UfoObj::none)
+        })
+        .unwrap_or_else(|_| UfoObj::none())
+    }
+
+    /// Find the UFO holding `ptr` and the element it points into. `out_byte_offset`
+    /// is the offset of `ptr` within that element. Pointers into the header or its
+    /// padding return InHeader, and pointers past the last element OutOfBounds,
+    /// leaving the outputs untouched.
+    ///
+    /// # Safety
+    ///
+    /// Each output must be NULL or valid for writing. `out_obj` is overwritten
+    /// without dropping whatever it held.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_lookup_address(
+        &self,
+        ptr: usize,
+        out_obj: *mut UfoObj,
+        out_index: *mut usize,
+        out_byte_offset: *mut usize,
+    ) -> UfoErrorCode {
+        catch_error(|| {
+            let core = match self.running() {
+                Ok(core) => core,
+                Err(e) => return e,
+            };
+            if out_obj.is_null() || out_index.is_null() || out_byte_offset.is_null() {
+                return fail(UfoErrorCode::NullHandle, "null output pointer");
+            }
+            let ufo = match core.the_core.get_ufo_by_address(ptr) {
+                Ok(ufo) => ufo,
+                Err(e) => {
+                    return fail(UfoErrorCode::UnknownAddress, format!("{:#x}: {:?}", ptr, e))
+                }
+            };
+
+            let (body, stride, element_ct) = {
+                let locked = ufo.read().expect("can't lock ufo");
+                (
+                    locked.body_ptr() as usize,
+                    locked.config.stride().alignment_quantum().bytes,
+                    locked.config.element_ct().total().elements,
+                )
+            };
+            if ptr < body {
+                return fail(
+                    UfoErrorCode::InHeader,
+                    format!("{:#x} is in the header", ptr),
+                );
+            }
+
+            let offset = ptr - body;
+            if offset / stride >= element_ct {
+                return fail(
+                    UfoErrorCode::OutOfBounds,
+                    format!("{:#x} is past the last element", ptr),
+                );
+            }
+            *out_index = offset / stride;
+            *out_byte_offset = offset % stride;
+            std::ptr::write(out_obj, UfoObj::new(ufo, core));
+            UfoErrorCode::Ok
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// Call `cb` once for every live UFO. The set is snapshotted first and `cb`
+    /// runs without any core locks held, so it may call back into the UFO API.
+    /// Returns 0 when every UFO was visited, the callback's non-zero value when
+    /// it stopped early, or a UfoErrorCode if the core handle is unusable.
+    #[no_mangle]
+    pub extern "C" fn ufo_core_for_each(
+        &self,
+        data: UfoForEachData,
+        cb: UfoForEachCallback,
+    ) -> i32 {
+        catch_error(|| {
+            let core = match self.running() {
+                Ok(core) => core,
+                Err(e) => return e as i32,
+            };
+
+            let ids: Vec<UfoId> = core
+                .data_map
+                .read()
+                .expect("can't lock map")
+                .keys()
+                .copied()
+                .collect();
+            let live: Vec<(u64, usize, usize)> = ids
+                .into_iter()
+                .filter_map(|id| core.live_ufo_by_id(id))
+                .filter_map(|ufo| {
+                    let ufo = ufo.read().ok()?;
+                    Some((
+                        ufo.id.0,
+                        ufo.body_ptr() as usize,
+                        ufo.config.element_ct().total().elements,
+                    ))
+                })
+                .collect();
+
+            live.into_iter()
+                .map(|(id, body, element_ct)| cb(data, id, body, element_ct))
+                .find(|ret| *ret != 0)
+                .unwrap_or(0)
+        })
+        .unwrap_or(UfoErrorCode::Panic as i32)
+    }
+
+    /// Look a UFO up by the id reported by ufo_id. Unknown and freed ids give
+    /// the null object.
+    #[no_mangle]
+    pub extern "C" fn ufo_get_by_id(&self, id: u64) -> UfoObj {
+        catch_error(|| {
+            self.running()
+                .ok()
+                .and_then(|core| {
+                    let ufo = core.live_ufo_by_id(UfoId(id));
+                    if ufo.is_none() {
+                        set_last_error(format!("no live UFO with id {}", id));
+                    }
+                    ufo.map(|ufo| UfoObj::new(ufo, core))
                 })
                 .unwrap_or_else(UfoObj::none)
         })
@@ -105,11 +807,11 @@ impl UfoCore {
 
     #[no_mangle]
     pub extern "C" fn ufo_get_params(&self, ufo: &UfoObj, params: *mut UfoParameters) -> i32 {
-        return std::panic::catch_unwind(|| {
+        return catch_error(|| {
             self.deref()
                 .zip(ufo.deref())
                 .and_then(|(core, ufo)| {
-                    let ufo = ufo.read().expect("can't lock ufo");
+                    let ufo = ufo.ufo.read().expect("can't lock ufo");
                     let map = core.data_map.read().expect("can't lock map");
 
                     let ufo_dat = map.get(&ufo.id)?;
@@ -134,8 +836,9 @@ impl UfoCore {
 
     #[no_mangle]
     pub extern "C" fn ufo_address_is_ufo_object(&self, ptr: *mut libc::c_void) -> bool {
-        std::panic::catch_unwind(|| {
-            self.deref()
+        catch_error(|| {
+            self.running()
+                .ok()
                 .and_then(|core| {
                     core.the_core.get_ufo_by_address(ptr as usize).ok()?; // don't care about the error, just doing an is-UFO
                     Some(true)
@@ -147,11 +850,279 @@ impl UfoCore {
 
     #[no_mangle]
     pub extern "C" fn ufo_new_object(&self, prototype: &UfoParameters) -> UfoObj {
-        std::panic::catch_unwind(|| {
+        self.new_callout_object(prototype, None)
+    }
+
+    /// Like ufo_new_object, but populated with `populate_fn`, which is also told
+    /// the size of the buffer it may fill and the id of the UFO. `prototype.populate_fn`
+    /// is ignored and may be NULL.
+    #[no_mangle]
+    pub extern "C" fn ufo_new_object_v2(
+        &self,
+        prototype: &UfoParameters,
+        populate_fn: UfoPopulateCalloutV2,
+    ) -> UfoObj {
+        self.new_object(prototype, Populator::CalloutV2(populate_fn), None)
+    }
+
+    /// A UFO whose elements all start as zero, filled without calling into C.
+    /// Only the geometry and writeback listener of `prototype` are used.
+    #[no_mangle]
+    pub extern "C" fn ufo_new_object_zeroed(&self, prototype: &UfoParameters) -> UfoObj {
+        self.new_object(prototype, Populator::Zero, None)
+    }
+
+    /// A UFO whose every element starts as `pattern`, which must be exactly
+    /// `prototype.element_size` bytes. The pattern is copied, so the caller may
+    /// release it on return. Unmodified chunks hash the same on eviction and are
+    /// never written back.
+    ///
+    /// # Safety
+    ///
+    /// `pattern` must be NULL or valid for reading `pattern_len` bytes.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_new_object_constant(
+        &self,
+        prototype: &UfoParameters,
+        pattern: *const u8,
+        pattern_len: usize,
+    ) -> UfoObj {
+        if pattern.is_null() || pattern_len != prototype.element_size {
+            set_last_error(format!(
+                "constant pattern is {} bytes, the element size is {}",
+                pattern_len, prototype.element_size
+            ));
+            return UfoObj::none();
+        }
+        let pattern = std::slice::from_raw_parts(pattern, pattern_len).to_vec();
+        self.new_object(prototype, Populator::Constant(pattern), None)
+    }
+
+    /// A UFO whose body is read from the file at `path`, starting `file_offset`
+    /// bytes in. The file is only read; modified chunks go to the normal
+    /// writeback file. The file is held open, so replacing it on disk does not
+    /// affect the UFO, and a file truncated while in use reads as zeros past
+    /// its new end.
+    ///
+    /// # Safety
+    ///
+    /// `path` must be NULL or a NUL terminated string.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_new_object_from_file(
+        &self,
+        prototype: &UfoParameters,
+        path: *const libc::c_char,
+        file_offset: u64,
+    ) -> UfoObj {
+        if path.is_null() {
+            set_last_error("null file path");
+            return UfoObj::none();
+        }
+        let path = std::ffi::CStr::from_ptr(path)
+            .to_string_lossy()
+            .into_owned();
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                set_last_error(format!("unable to open {}: {}", path, e));
+                return UfoObj::none();
+            }
+        };
+
+        let body_size = (prototype.element_ct as u64).checked_mul(prototype.element_size as u64);
+        let file_size = file.metadata().map(|m| m.len());
+        match (body_size, file_size) {
+            (Some(body_size), Ok(file_size))
+                if file_offset.saturating_add(body_size) <= file_size =>
+            {
+                self.new_object(
+                    prototype,
+                    Populator::File {
+                        file,
+                        offset: file_offset,
+                    },
+                    None,
+                )
+            }
+            (_, Err(e)) => {
+                set_last_error(format!("unable to stat {}: {}", path, e));
+                UfoObj::none()
+            }
+            _ => {
+                set_last_error(format!(
+                    "{} is too short for {} elements of {} bytes at offset {}",
+                    path, prototype.element_ct, prototype.element_size, file_offset
+                ));
+                UfoObj::none()
+            }
+        }
+    }
+
+    /// Like ufo_new_object, but `finalizer` is called on `populate_data` exactly once,
+    /// after the last possible populate call. That happens when the UFO is freed,
+    /// when its last handle is dropped, or when the core shuts down. It also runs
+    /// if the allocation fails, so the caller must not release `populate_data` itself.
+    #[no_mangle]
+    pub extern "C" fn ufo_new_object_with_finalizer(
+        &self,
+        prototype: &UfoParameters,
+        finalizer: UfoPopulateDataFinalizer,
+    ) -> UfoObj {
+        // built first, so every way of failing below still finalizes
+        let finalizer = finalizer.map(|finalizer| FinalizeOnDrop {
+            data: prototype.populate_data as usize,
+            finalizer,
+        });
+        self.new_callout_object(prototype, finalizer)
+    }
+
+    /// Like ufo_new_object, but `header_fn` is called with `populate_data` and the
+    /// header before the handle is returned, so no other thread can see a
+    /// half-written header. If `header_fn` returns nonzero the UFO is freed and
+    /// a null handle returned.
+    #[no_mangle]
+    pub extern "C" fn ufo_new_object_with_header(
+        &self,
+        prototype: &UfoParameters,
+        header_fn: UfoHeaderPopulateCallout,
+    ) -> UfoObj {
+        let obj = self.new_callout_object(prototype, None);
+        if obj.ufo_is_error() || prototype.header_size == 0 {
+            return obj;
+        }
+
+        let header = obj.ufo_header_ptr().cast();
+        let ret = catch_error(|| header_fn(prototype.populate_data, header, prototype.header_size))
+            .unwrap_or(-1);
+        if ret != 0 {
+            obj.ufo_free();
+            set_last_error(format!("header populate function failed with {}", ret));
+            return UfoObj::none();
+        }
+        obj
+    }
+
+    /// Create `n` UFOs that share `prototype` but have their own element count
+    /// from `cts` and populate data from `populate_datas`, or the prototype's
+    /// populate_data for all of them if `populate_datas` is NULL. The handles
+    /// are written to `out`, which must have room for `n`.
+    ///
+    /// Returns the number of UFOs created. If that is less than `n`, creating
+    /// the UFO at that index failed, the ones before it have been freed, and
+    /// nothing in `out` needs releasing.
+    ///
+    /// # Safety
+    ///
+    /// `cts` and `populate_datas`, unless NULL, must be valid for reading `n`
+    /// values, and `out` for writing `n` handles.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_new_object_batch(
+        &self,
+        prototype: &UfoParameters,
+        cts: *const usize,
+        n: usize,
+        populate_datas: *const UfoPopulateData,
+        out: *mut UfoObj,
+    ) -> usize {
+        if cts.is_null() || out.is_null() {
+            set_last_error("null element count or output array");
+            return 0;
+        }
+
+        for i in 0..n {
+            let populate_data = if populate_datas.is_null() {
+                prototype.populate_data
+            } else {
+                *populate_datas.add(i)
+            };
+            let params = UfoParameters {
+                element_ct: *cts.add(i),
+                populate_data,
+                ..*prototype
+            };
+
+            let obj = self.new_callout_object(&params, None);
+            if obj.ufo_is_error() {
+                for j in 0..i {
+                    out.add(j).read().ufo_free();
+                }
+                return i;
+            }
+            out.add(i).write(obj);
+        }
+        n
+    }
+
+    fn new_callout_object(
+        &self,
+        prototype: &UfoParameters,
+        finalizer: Option<FinalizeOnDrop>,
+    ) -> UfoObj {
+        match prototype.populate_fn {
+            Some(populate_fn) => {
+                self.new_object(prototype, Populator::Callout(populate_fn), finalizer)
+            }
+            None => {
+                set_last_error("null populate_fn");
+                UfoObj::none()
+            }
+        }
+    }
+
+    fn check_size_cap(&self, prototype: &UfoParameters) -> Result<(), UfoConfigError> {
+        let cap = self.deref().and_then(|core| core.max_object_bytes);
+        // validate has already ruled out overflow
+        let bytes = prototype.header_size + prototype.element_size * prototype.element_ct;
+        match cap {
+            Some(cap) if bytes > cap => Err(UfoConfigError::TooLarge { bytes, cap }),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn new_object(
+        &self,
+        prototype: &UfoParameters,
+        populator: Populator,
+        finalizer: Option<FinalizeOnDrop>,
+    ) -> UfoObj {
+        if let Err(e) = prototype
+            .validate()
+            .and_then(|()| self.check_size_cap(prototype))
+        {
+            set_last_error(format!("invalid UFO parameters: {}", e));
+            return UfoObj::none();
+        }
+
+        // a boxed Rust closure is not UnwindSafe, but a panic below never leaves it half-run
+        let populator = AssertUnwindSafe(populator);
+        catch_error(|| {
             let populate_data = prototype.populate_data as usize;
-            let populate_fn = prototype.populate_fn;
+            let populate_fn = populator.callout();
+            let stride = prototype.element_size;
+            let canaries = self.deref().is_some_and(|core| core.populate_canaries);
+            // only known once allocated, but nothing can fault before we return the handle
+            let ufo_id = Arc::new(AtomicU64::new(0));
+            let populate_ufo_id = ufo_id.clone();
+            let stats = Arc::new(PopulateCounters::default());
+            let populate_stats = stats.clone();
+            let populate_totals = self.deref().map(|core| core.populate_totals.clone());
             let populate = move |start, end, to_populate| {
-                let ret = populate_fn(populate_data as *mut c_void, start, end, to_populate);
+                // the core drops this closure after its last call, taking the finalizer with it
+                let _ = &finalizer;
+                let data = populate_data as *mut c_void;
+                let ufo_id = populate_ufo_id.load(Ordering::Acquire);
+                let _populating = PopulateGuard::enter();
+                let started = std::time::Instant::now();
+                let ret = if canaries {
+                    populator.populate_checked(data, stride, ufo_id, start, end, to_populate)
+                } else {
+                    populator.populate(data, stride, ufo_id, start, end, to_populate)
+                };
+                let elapsed = started.elapsed();
+                populate_stats.record((end - start) * stride, elapsed, ret == 0);
+                if let Some(totals) = &populate_totals {
+                    totals.record((end - start) * stride, elapsed, ret == 0);
+                }
 
                 if ret != 0 {
                     Err(UfoPopulateError)
@@ -180,28 +1151,71 @@ impl UfoCore {
                 writeback_listener,
             };
 
-            self.deref()
+            self.running()
+                .ok()
                 .and_then(move |core| {
-                    let ufo = core.the_core.allocate_ufo(params.new_config());
+                    let config = params.new_config();
+                    let stride = config.stride().alignment_quantum().bytes;
+                    let chunk_bytes = config
+                        .elements_loaded_at_once()
+                        .alignment_quantum()
+                        .elements
+                        * stride;
+                    // the core can't make room for a chunk that doesn't fit between the watermarks
+                    let budget = core.high_watermark - core.low_watermark;
+                    if chunk_bytes >= budget {
+                        set_last_error(format!(
+                            "invalid UFO parameters: {}",
+                            UfoConfigError::ChunkTooLarge {
+                                chunk_bytes,
+                                budget
+                            }
+                        ));
+                        return None;
+                    }
+
+                    let warnings = core.lint(Some(LintObject {
+                        stride,
+                        element_ct: config.element_ct().total().elements,
+                        chunk_bytes,
+                    }));
+                    if core.strict_lints.load(Ordering::Acquire) {
+                        if let Some(w) = warnings.iter().find(|w| w.strict) {
+                            set_last_error(format!("rejected by strict lint {}", w));
+                            return None;
+                        }
+                    }
+
+                    let ufo = core.the_core.allocate_ufo(config);
                     match ufo {
                         Ok(ufo) => {
+                            // UFO lock before map lock, the same order as start_free
+                            let id = ufo.read().expect("can't get read lock").id;
                             let mut data_map =
                                 core.data_map.write().expect("unable to lock data map");
-                            let id = ufo.read().expect("can't get read lock").id;
+                            ufo_id.store(id.0, Ordering::Release);
                             data_map.insert(
                                 id,
                                 CParams {
                                     populate_data: prototype.populate_data,
-                                    populate_fn: prototype.populate_fn,
+                                    populate_fn,
 
                                     writeback_listener_data: prototype.writeback_listener_data,
                                     writeback_listener: prototype.writeback_listener,
+
+                                    ufo: Arc::downgrade(&ufo),
+                                    stats,
+                                    header_restores: Arc::new(AtomicUsize::new(0)),
                                 },
                             );
 
-                            Some(UfoObj::wrap(ufo))
+                            core.ufos_created.fetch_add(1, Ordering::Relaxed);
+                            Some(UfoObj::new(ufo, core))
+                        }
+                        Err(e) => {
+                            set_last_error(format!("unable to allocate UFO: {:?}", e));
+                            None
                         }
-                        _ => None,
                     }
                 })
                 .unwrap_or_else(|| UfoObj::none())
@@ -215,8 +1229,9 @@ impl UfoCore {
         callback_data: UfoEventCallbackData,
         callback: UfoEventCallback,
     ) -> bool {
-        std::panic::catch_unwind(|| {
-            self.deref()
+        catch_error(|| {
+            self.running()
+                .ok()
                 .and_then(|core| {
                     let masked_callback_data = callback_data as usize;
                     let cb: Option<Box<ufo_core::UfoEventConsumer>> = Some(Box::new(move |e| {
@@ -234,8 +1249,9 @@ impl UfoCore {
 
     #[no_mangle]
     pub extern "C" fn ufo_clear_event_handler(&self) -> bool {
-        std::panic::catch_unwind(|| {
-            self.deref()
+        catch_error(|| {
+            self.running()
+                .ok()
                 .and_then(|core| {
                     core.the_core
                         .new_event_callback(None)
@@ -247,3 +1263,291 @@ impl UfoCore {
         .unwrap_or(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::test_util::*;
+
+    /// Counts calls in the AtomicUsize that `data` points at
+    extern "C" fn count_finalize(data: UfoPopulateData) {
+        unsafe { &*(data as *const AtomicUsize) }.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn counted_params(count: &AtomicUsize, element_ct: usize) -> UfoParameters {
+        UfoParameters {
+            populate_data: count as *const AtomicUsize as *mut c_void,
+            ..u64_params(0, element_ct)
+        }
+    }
+
+    #[test]
+    fn lookup_address() {
+        let core = test_core();
+        let obj = core.ufo_new_object(&u64_params(16, 10));
+        let body = obj.ufo_body_ptr() as usize;
+        let mut found = UfoObj::none();
+        let (mut index, mut offset) = (0, 0);
+
+        assert_eq!(UfoErrorCode::Ok, unsafe {
+            core.ufo_lookup_address(body + 8 * 9 + 3, &mut found, &mut index, &mut offset)
+        });
+        assert_eq!((9, 3), (index, offset));
+        assert_eq!(obj.ufo_body_ptr(), found.ufo_body_ptr());
+        found.ufo_release();
+
+        let mut found = UfoObj::none();
+        assert_eq!(UfoErrorCode::InHeader, unsafe {
+            core.ufo_lookup_address(body - 1, &mut found, &mut index, &mut offset)
+        });
+        // the body is padded to a page, its tail is not an element
+        assert_ne!(UfoErrorCode::Ok, unsafe {
+            core.ufo_lookup_address(body + 8 * 10, &mut found, &mut index, &mut offset)
+        });
+        assert!(found.ufo_is_error());
+        assert_eq!(UfoErrorCode::NullHandle, unsafe {
+            core.ufo_lookup_address(body, std::ptr::null_mut(), &mut index, std::ptr::null_mut())
+        });
+
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn core_config() {
+        let core = test_core();
+        assert_eq!(UfoErrorCode::NullHandle, unsafe {
+            core.ufo_core_config(std::ptr::null_mut())
+        });
+        let mut config = std::mem::MaybeUninit::<UfoCoreConfigC>::uninit();
+        assert_eq!(UfoErrorCode::Ok, unsafe {
+            core.ufo_core_config(config.as_mut_ptr())
+        });
+        let config = unsafe { config.assume_init() };
+        assert_eq!(
+            (16 << 20, 64 << 20),
+            (config.low_watermark, config.high_watermark)
+        );
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn forked_child_sees_the_core_as_forked() {
+        let core = test_core();
+        assert!(!core.deref().unwrap().is_forked());
+        let obj = core.ufo_new_object(&u64_params(0, 1 << 20));
+        let populated = 0;
+        let mut value = 0u64;
+        assert_eq!(UfoErrorCode::Ok, unsafe {
+            obj.ufo_read(8 * populated, 8, (&mut value as *mut u64).cast())
+        });
+        // far enough from the element read above to be in another chunk
+        let unpopulated = (1 << 20) - 1;
+
+        match unsafe { libc::fork() } {
+            0 => {
+                // no panics here, the child must not unwind into the test harness
+                let refused = |index: usize| {
+                    let mut value = 0u64;
+                    let read =
+                        unsafe { obj.ufo_read(8 * index, 8, (&mut value as *mut u64).cast()) };
+                    obj.ufo_element_ptr(index).is_null()
+                        && read == UfoErrorCode::CoreShutdown
+                        && obj.ufo_prefault(index, index + 1) == UfoErrorCode::CoreShutdown
+                };
+                let forked = core.deref().unwrap().is_forked()
+                    && !obj.ufo_is_valid()
+                    && refused(populated)
+                    && refused(unpopulated);
+                unsafe { libc::_exit(if forked { 0 } else { 1 }) };
+            }
+            child => {
+                assert!(child > 0, "fork failed");
+                let mut status = 0;
+                assert_eq!(child, unsafe { libc::waitpid(child, &mut status, 0) });
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(0, libc::WEXITSTATUS(status));
+            }
+        }
+        assert!(!core.deref().unwrap().is_forked());
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        core.ufo_core_free();
+    }
+
+    fn config_warnings(core: &UfoCore) -> String {
+        let len = unsafe { core.ufo_core_config_warnings(std::ptr::null_mut(), 0) };
+        let mut buf = vec![0u8; len + 1];
+        unsafe { core.ufo_core_config_warnings(buf.as_mut_ptr().cast(), buf.len()) };
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn strict_lints() {
+        let path =
+            std::ffi::CString::new(std::env::temp_dir().to_string_lossy().into_owned()).unwrap();
+        let core = unsafe { UfoCore::ufo_new_core(path.as_ptr(), 1 << 20, 3 << 20) };
+        assert!(!core.ufo_core_is_error());
+        // 1.5MiB chunks in a 2MiB gap
+        let params = UfoParameters {
+            min_load_ct: 3 << 16,
+            ..u64_params(0, 3 << 17)
+        };
+
+        let first = core.ufo_new_object(&params);
+        let second = core.ufo_new_object(&params);
+        assert!(!first.ufo_is_error() && !second.ufo_is_error());
+        let warnings = config_warnings(&core);
+        assert_eq!(
+            1,
+            warnings.matches("UFO_LINT_CHUNK_EXCEEDS_HALF_GAP").count()
+        );
+        assert_eq!(
+            1,
+            warnings.matches("UFO_LINT_GAP_UNDER_FOUR_CHUNKS").count()
+        );
+
+        assert!(core.ufo_core_set_strict_lints(true));
+        assert!(core.ufo_new_object(&params).ufo_is_error());
+        let error = unsafe { std::ffi::CStr::from_ptr(ufo_last_error()) };
+        assert!(error
+            .to_string_lossy()
+            .contains("UFO_LINT_CHUNK_EXCEEDS_HALF_GAP"));
+
+        // non-strict warnings still allocate
+        let quiet = UfoParameters {
+            min_load_ct: 1 << 16,
+            ..u64_params(0, 3 << 17)
+        };
+        let third = core.ufo_new_object(&quiet);
+        assert!(!third.ufo_is_error());
+
+        for obj in [first, second, third] {
+            assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        }
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn chunk_larger_than_the_watermark_gap() {
+        let path =
+            std::ffi::CString::new(std::env::temp_dir().to_string_lossy().into_owned()).unwrap();
+        let core = unsafe { UfoCore::ufo_new_core(path.as_ptr(), 1 << 20, 3 << 20) };
+        assert!(!core.ufo_core_is_error());
+        // a 2MiB chunk exactly fills the 2MiB gap, leaving no room to evict into
+        let params = UfoParameters {
+            min_load_ct: 1 << 18,
+            ..u64_params(0, 1 << 19)
+        };
+
+        ufo_clear_error();
+        assert!(core.ufo_new_object(&params).ufo_is_error());
+        let error = unsafe { std::ffi::CStr::from_ptr(ufo_last_error()) };
+        assert_eq!(
+            "invalid UFO parameters: chunk size 2097152 exceeds the memory budget of 2097152 \
+             between the watermarks",
+            error.to_string_lossy()
+        );
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn drain_waits_for_async_operations() {
+        let core = test_core();
+        for _ in 0..100 {
+            let obj = core.ufo_new_object(&u64_params(16, 1000));
+            assert_eq!(UfoErrorCode::Ok, obj.ufo_reset_async());
+            assert_eq!(UfoErrorCode::Ok, obj.ufo_free_async());
+        }
+        assert_eq!(UfoErrorCode::Ok, core.ufo_core_drain(10_000));
+        // finished operations are counted, not kept
+        let counts = *core.deref().unwrap().pending_ops.counts.lock().unwrap();
+        assert_eq!((200, 200), counts);
+        assert_eq!(UfoErrorCode::Ok, core.ufo_core_drain(0));
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn finalizer_runs_when_parameters_are_rejected() {
+        let core = test_core();
+
+        let count = AtomicUsize::new(0);
+        let invalid = UfoParameters {
+            element_size: 0,
+            ..counted_params(&count, 100)
+        };
+        assert!(core
+            .ufo_new_object_with_finalizer(&invalid, Some(count_finalize))
+            .ufo_is_error());
+        assert_eq!(1, count.load(Ordering::SeqCst));
+
+        let count = AtomicUsize::new(0);
+        let no_populate = UfoParameters {
+            populate_fn: None,
+            ..counted_params(&count, 100)
+        };
+        assert!(core
+            .ufo_new_object_with_finalizer(&no_populate, Some(count_finalize))
+            .ufo_is_error());
+        assert_eq!(1, count.load(Ordering::SeqCst));
+
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn finalizer_runs_when_over_the_size_cap() {
+        let path =
+            std::ffi::CString::new(std::env::temp_dir().to_string_lossy().into_owned()).unwrap();
+        let core = unsafe {
+            UfoCore::ufo_new_core_with_params(&UfoCoreParams {
+                writeback_temp_path: path.as_ptr(),
+                low_watermark: 16 << 20,
+                high_watermark: 64 << 20,
+                populate_threads: 0,
+                flags: 0,
+                max_object_bytes: 4096,
+                reserved: [0; 4],
+            })
+        };
+        assert!(!core.ufo_core_is_error());
+
+        let count = AtomicUsize::new(0);
+        assert!(core
+            .ufo_new_object_with_finalizer(&counted_params(&count, 1000), Some(count_finalize))
+            .ufo_is_error());
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn finalizer_runs_once_on_double_free() {
+        let core = test_core();
+        let count = AtomicUsize::new(0);
+        let obj =
+            core.ufo_new_object_with_finalizer(&counted_params(&count, 1000), Some(count_finalize));
+        assert!(!obj.ufo_is_error());
+        let other = obj.ufo_clone();
+
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        assert_eq!(UfoErrorCode::Ok, other.ufo_free());
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        core.ufo_core_free();
+        assert_eq!(1, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn finalizer_runs_once_across_shutdown() {
+        let core = test_core();
+        let count = AtomicUsize::new(0);
+        let obj =
+            core.ufo_new_object_with_finalizer(&counted_params(&count, 1000), Some(count_finalize));
+        let other = obj.ufo_clone();
+
+        assert_eq!(0, core.ufo_core_shutdown());
+        assert_eq!(UfoErrorCode::CoreShutdown, obj.ufo_free());
+        other.ufo_release();
+        core.ufo_core_free();
+        assert_eq!(1, count.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,77 @@
+use std::{any::Any, cell::RefCell, ffi::CString, fmt::Display, panic::UnwindSafe};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Why a call failed. Success is 0, so callers that only check `!= 0` keep working.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UfoErrorCode {
+    Ok = 0,
+    NullHandle = -1,
+    OutOfBounds = -2,
+    ReadOnly = -3,
+    CoreShutdown = -4,
+    LockPoisoned = -5,
+    /// not yet reported, ufo_core does not surface populate failures to callers
+    PopulateFailed = -6,
+    ChannelBroken = -7,
+    Panic = -8,
+    UnknownAddress = -9,
+    InHeader = -10,
+    Timeout = -11,
+    /// UFO memory was requested from inside a populate callout
+    ReentrantPopulate = -12,
+    /// the header is being restored after a reset, see ufo_reset_async
+    ResetPending = -13,
+    /// the call needs support ufo_core does not have yet
+    Unsupported = -14,
+}
+
+/// Record `detail` as the last error and hand back `code`
+pub(crate) fn fail<D: Display>(code: UfoErrorCode, detail: D) -> UfoErrorCode {
+    set_last_error(format!("{:?}: {}", code, detail));
+    code
+}
+
+pub(crate) fn set_last_error<D: Display>(err: D) {
+    let msg = err.to_string().replace('\0', "");
+    let msg = CString::new(msg).expect("nul bytes were removed");
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// catch_unwind, but the panic message is kept as the last error
+pub(crate) fn catch_error<F, R>(f: F) -> Result<R, ()>
+where
+    F: FnOnce() -> R + UnwindSafe,
+{
+    std::panic::catch_unwind(f).map_err(|payload| set_last_error(panic_message(&*payload)))
+}
+
+/// The message from the last failing call on this thread, or null if there
+/// was none. Valid until the next failing call or ufo_clear_error on this thread.
+#[no_mangle]
+pub extern "C" fn ufo_last_error() -> *const libc::c_char {
+    LAST_ERROR.with(|e| {
+        e.borrow()
+            .as_ref()
+            .map(|msg| msg.as_ptr())
+            .unwrap_or_else(std::ptr::null)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn ufo_clear_error() {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+}
@@ -1,21 +1,23 @@
 #[macro_use]
 mod ctype_wrapper;
 
+mod capabilities;
+pub use crate::capabilities::*;
+mod error;
+pub use crate::error::*;
 mod core;
 pub use crate::core::*;
+mod lint;
+mod logging;
+pub use crate::logging::*;
+mod populate;
 mod prototype;
 pub use crate::prototype::*;
 mod object;
 pub use crate::object::*;
+#[cfg(feature = "rust-api")]
+pub mod rust_api;
+#[cfg(test)]
+mod test_util;
 
 pub use ufo_core::{UfoEvent, UfoEventandTimestamp, UfoUnloadDisposition};
-
-#[no_mangle]
-pub extern "C" fn ufo_begin_log() {
-    stderrlog::new()
-        // .module("ufo_core")
-        .verbosity(4)
-        .timestamp(stderrlog::Timestamp::Millisecond)
-        .init()
-        .unwrap();
-}
@@ -0,0 +1,222 @@
+use std::fmt;
+
+/// What a lint rule gets to look at
+pub(crate) struct LintInput<'a> {
+    pub(crate) low_watermark: usize,
+    pub(crate) high_watermark: usize,
+    pub(crate) writeback_temp_path: &'a str,
+    /// (free, total) bytes under writeback_temp_path, None if unknown
+    pub(crate) disk_space: Option<(u64, u64)>,
+    /// None when linting the core on its own, Some for each allocation
+    pub(crate) object: Option<LintObject>,
+}
+
+pub(crate) struct LintObject {
+    pub(crate) stride: usize,
+    pub(crate) element_ct: usize,
+    pub(crate) chunk_bytes: usize,
+}
+
+/// Legal but almost certainly a mistake
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct ConfigWarning {
+    pub(crate) code: &'static str,
+    pub(crate) message: String,
+    pub(crate) suggestion: &'static str,
+    /// strict mode turns this warning into an allocation error
+    pub(crate) strict: bool,
+}
+
+impl fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} ({})", self.code, self.message, self.suggestion)
+    }
+}
+
+struct LintRule {
+    code: &'static str,
+    suggestion: &'static str,
+    strict: bool,
+    check: fn(&LintInput) -> Option<String>,
+}
+
+/// Add new lints here
+static LINT_RULES: &[LintRule] = &[
+    LintRule {
+        code: "UFO_LINT_CHUNK_EXCEEDS_HALF_GAP",
+        suggestion: "lower min_load_ct or widen the watermark gap",
+        strict: true,
+        check: |input| {
+            let obj = input.object.as_ref()?;
+            let gap = input.high_watermark - input.low_watermark;
+            (obj.chunk_bytes > gap / 2).then(|| {
+                format!(
+                    "chunk of {} bytes is more than half the watermark gap of {} bytes",
+                    obj.chunk_bytes, gap
+                )
+            })
+        },
+    },
+    LintRule {
+        code: "UFO_LINT_GAP_UNDER_FOUR_CHUNKS",
+        suggestion: "widen the watermark gap to hold at least four chunks",
+        strict: false,
+        check: |input| {
+            let obj = input.object.as_ref()?;
+            let gap = input.high_watermark - input.low_watermark;
+            (obj.chunk_bytes.saturating_mul(4) > gap).then(|| {
+                format!(
+                    "watermark gap of {} bytes holds fewer than 4 chunks of {} bytes",
+                    gap, obj.chunk_bytes
+                )
+            })
+        },
+    },
+    LintRule {
+        code: "UFO_LINT_TINY_STRIDE_HUGE_COUNT",
+        suggestion: "pack several values per element to reduce chunk bookkeeping",
+        strict: false,
+        check: |input| {
+            let obj = input.object.as_ref()?;
+            (obj.stride == 1 && obj.element_ct >= 1 << 30)
+                .then(|| format!("stride of 1 byte with {} elements", obj.element_ct))
+        },
+    },
+    LintRule {
+        code: "UFO_LINT_WRITEBACK_DISK_NEARLY_FULL",
+        suggestion: "point writeback_temp_path at a volume with more free space",
+        strict: false,
+        check: |input| {
+            if input.object.is_some() {
+                return None;
+            }
+            let (free, total) = input.disk_space?;
+            (free < input.high_watermark as u64 || free < total / 20).then(|| {
+                format!(
+                    "only {} of {} bytes free under {}",
+                    free, total, input.writeback_temp_path
+                )
+            })
+        },
+    },
+];
+
+/// (free, total) bytes on the volume holding `path`
+pub(crate) fn disk_space(path: &str) -> Option<(u64, u64)> {
+    let path = std::ffi::CString::new(path).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let frsize = stat.f_frsize as u64;
+    Some((stat.f_bavail as u64 * frsize, stat.f_blocks as u64 * frsize))
+}
+
+pub(crate) fn lint_config(input: &LintInput) -> Vec<ConfigWarning> {
+    LINT_RULES
+        .iter()
+        .filter_map(|rule| {
+            (rule.check)(input).map(|message| ConfigWarning {
+                code: rule.code,
+                message,
+                suggestion: rule.suggestion,
+                strict: rule.strict,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a terabyte disk, half free
+    const ROOMY_DISK: Option<(u64, u64)> = Some((1 << 39, 1 << 40));
+
+    fn codes_on_disk(
+        low_watermark: usize,
+        high_watermark: usize,
+        disk_space: Option<(u64, u64)>,
+        object: Option<LintObject>,
+    ) -> Vec<&'static str> {
+        lint_config(&LintInput {
+            low_watermark,
+            high_watermark,
+            writeback_temp_path: "/writeback",
+            disk_space,
+            object,
+        })
+        .into_iter()
+        .map(|w| w.code)
+        .collect()
+    }
+
+    fn codes(
+        low_watermark: usize,
+        high_watermark: usize,
+        object: Option<LintObject>,
+    ) -> Vec<&'static str> {
+        codes_on_disk(low_watermark, high_watermark, ROOMY_DISK, object)
+    }
+
+    fn object(stride: usize, element_ct: usize, chunk_bytes: usize) -> Option<LintObject> {
+        Some(LintObject {
+            stride,
+            element_ct,
+            chunk_bytes,
+        })
+    }
+
+    #[test]
+    fn sane_config_is_quiet() {
+        assert!(codes(16 << 20, 64 << 20, None).is_empty());
+        assert!(codes(16 << 20, 64 << 20, object(8, 1 << 20, 1 << 20)).is_empty());
+    }
+
+    #[test]
+    fn each_rule_fires() {
+        assert_eq!(
+            vec![
+                "UFO_LINT_CHUNK_EXCEEDS_HALF_GAP",
+                "UFO_LINT_GAP_UNDER_FOUR_CHUNKS"
+            ],
+            codes(0, 64 << 20, object(8, 1 << 30, 40 << 20))
+        );
+        assert_eq!(
+            vec!["UFO_LINT_GAP_UNDER_FOUR_CHUNKS"],
+            codes(0, 64 << 20, object(8, 1 << 30, 20 << 20))
+        );
+        assert_eq!(
+            vec!["UFO_LINT_TINY_STRIDE_HUGE_COUNT"],
+            codes(16 << 20, 64 << 20, object(1, 1 << 30, 4096))
+        );
+        // less free than the high watermark
+        assert_eq!(
+            vec!["UFO_LINT_WRITEBACK_DISK_NEARLY_FULL"],
+            codes_on_disk(16 << 20, 64 << 20, Some((32 << 20, 1 << 30)), None)
+        );
+        // under 5% free
+        assert_eq!(
+            vec!["UFO_LINT_WRITEBACK_DISK_NEARLY_FULL"],
+            codes_on_disk(16 << 20, 64 << 20, Some((1 << 30, 1 << 40)), None)
+        );
+    }
+
+    #[test]
+    fn only_the_core_checks_the_disk() {
+        let full = Some((0, 1 << 40));
+        assert!(codes_on_disk(16 << 20, 64 << 20, full, object(8, 1024, 4096)).is_empty());
+        // an unknown disk is not reported as full
+        assert!(codes_on_disk(16 << 20, 64 << 20, None, None).is_empty());
+    }
+
+    #[test]
+    fn strict_rules() {
+        let strict: Vec<_> = LINT_RULES
+            .iter()
+            .filter(|r| r.strict)
+            .map(|r| r.code)
+            .collect();
+        assert_eq!(vec!["UFO_LINT_CHUNK_EXCEEDS_HALF_GAP"], strict);
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    ffi::{CStr, CString},
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use libc::{c_char, c_void};
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::set_last_error;
+
+pub type UfoLogCallbackData = *mut c_void;
+/// Called with the level (1 error through 5 trace), the module the message came
+/// from, and the message. Both strings are only valid during the call.
+pub type UfoLogCallback = extern "C" fn(UfoLogCallbackData, u32, *const c_char, *const c_char);
+
+/// Where log lines go
+enum LogSink {
+    Stderr,
+    File(File),
+    Callback {
+        data: usize,
+        callback: UfoLogCallback,
+    },
+}
+
+/// The level is log's global max level so it can change at runtime
+struct UfoLogger {
+    sink: RwLock<LogSink>,
+}
+
+static LOGGER: UfoLogger = UfoLogger {
+    sink: RwLock::new(LogSink::Stderr),
+};
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+fn set_sink(sink: LogSink) {
+    // a panic while logging leaves the sink itself intact
+    let mut current = LOGGER.sink.write().unwrap_or_else(|e| e.into_inner());
+    *current = sink;
+}
+
+fn c_string(s: String) -> CString {
+    CString::new(s.replace('\0', "")).expect("nul bytes were removed")
+}
+
+/// 0 is errors only, then warnings, info, debug, and 4 and up is everything
+fn level_filter(verbosity: u32) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+impl Log for UfoLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let sink = self.sink.read().unwrap_or_else(|e| e.into_inner());
+        if let LogSink::Callback { data, callback } = *sink {
+            // unlocked first, so the callback can change the destination
+            drop(sink);
+            let target = c_string(record.target().to_string());
+            let msg = c_string(record.args().to_string());
+            callback(
+                data as *mut c_void,
+                record.level() as u32,
+                target.as_ptr(),
+                msg.as_ptr(),
+            );
+            return;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // one write per line so lines from different threads don't interleave
+        let line = format!(
+            "{}.{:03} - {} - {}\n",
+            now.as_secs(),
+            now.subsec_millis(),
+            record.level(),
+            record.args()
+        );
+        let _ = match &*sink {
+            LogSink::File(file) => (&*file).write_all(line.as_bytes()),
+            _ => std::io::stderr().write_all(line.as_bytes()),
+        };
+    }
+
+    fn flush(&self) {
+        let sink = self.sink.read().unwrap_or_else(|e| e.into_inner());
+        let _ = match &*sink {
+            LogSink::File(file) => (&*file).flush(),
+            _ => std::io::stderr().flush(),
+        };
+    }
+}
+
+/// Start logging to stderr at `verbosity` (0 errors only, 1 warnings, 2 info,
+/// 3 debug, 4 and up trace). Returns 0 when logging starts, 1 if it had already
+/// been started, in which case nothing changes, and -1 if some other logger was
+/// installed in this process first. Use ufo_set_log_level to change the level.
+#[no_mangle]
+pub extern "C" fn ufo_begin_log(verbosity: u32) -> i32 {
+    if INSTALLED
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return 1;
+    }
+
+    match log::set_logger(&LOGGER) {
+        Ok(()) => {
+            ufo_set_log_level(verbosity);
+            0
+        }
+        Err(e) => {
+            INSTALLED.store(false, Ordering::Release);
+            set_last_error(format!("unable to start logging: {}", e));
+            -1
+        }
+    }
+}
+
+/// Change the verbosity given to ufo_begin_log. Applies to messages logged
+/// after the call returns.
+#[no_mangle]
+pub extern "C" fn ufo_set_log_level(verbosity: u32) {
+    log::set_max_level(level_filter(verbosity));
+}
+
+/// Send log messages to `callback` instead of stderr. The callback is called
+/// from whichever thread logs, including the core's own threads, so it must be
+/// thread safe and must not touch UFO memory. It may switch the destination
+/// with the ufo_log_to_* calls. Takes effect once ufo_begin_log has been called.
+#[no_mangle]
+pub extern "C" fn ufo_log_to_callback(data: UfoLogCallbackData, callback: UfoLogCallback) {
+    set_sink(LogSink::Callback {
+        data: data as usize,
+        callback,
+    });
+}
+
+/// Append log messages to the file at `path`, creating it if needed. Returns 0,
+/// or -1 with a last error if the file can't be opened, leaving the current
+/// destination in place.
+///
+/// # Safety
+///
+/// `path` must be NULL or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ufo_log_to_file(path: *const c_char) -> i32 {
+    if path.is_null() {
+        set_last_error("null log file path");
+        return -1;
+    }
+    let path = CStr::from_ptr(path).to_string_lossy().into_owned();
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(file) => {
+            set_sink(LogSink::File(file));
+            0
+        }
+        Err(e) => {
+            set_last_error(format!("unable to open log file {}: {}", path, e));
+            -1
+        }
+    }
+}
+
+/// Send log messages back to stderr
+#[no_mangle]
+pub extern "C" fn ufo_log_to_stderr() {
+    set_sink(LogSink::Stderr);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    const MARKER: &str = "logging test marker";
+
+    extern "C" fn record(data: UfoLogCallbackData, _: u32, _: *const c_char, msg: *const c_char) {
+        let msg = unsafe { CStr::from_ptr(msg) }
+            .to_string_lossy()
+            .into_owned();
+        if msg.contains(MARKER) {
+            let seen = unsafe { &*(data as *const Mutex<Vec<String>>) };
+            seen.lock().unwrap().push(msg);
+        }
+    }
+
+    /// record, then send later lines back to stderr
+    extern "C" fn record_once(
+        data: UfoLogCallbackData,
+        level: u32,
+        target: *const c_char,
+        msg: *const c_char,
+    ) {
+        record(data, level, target, msg);
+        if unsafe { CStr::from_ptr(msg) }
+            .to_string_lossy()
+            .contains(MARKER)
+        {
+            ufo_log_to_stderr();
+        }
+    }
+
+    /// One test, since the logger and its level are global to the process
+    #[test]
+    fn begin_once_and_change_level() {
+        // other tests' threads may still log after this one returns
+        let seen: &'static Mutex<Vec<String>> = Box::leak(Box::new(Mutex::new(Vec::new())));
+        ufo_log_to_callback(seen as *const _ as *mut c_void, record);
+
+        assert_eq!(0, ufo_begin_log(1));
+        log::warn!("{} 1", MARKER);
+        log::info!("{} 2", MARKER);
+
+        // a second begin keeps the level
+        assert_eq!(1, ufo_begin_log(4));
+        log::info!("{} 3", MARKER);
+
+        ufo_set_log_level(2);
+        log::info!("{} 4", MARKER);
+        ufo_set_log_level(0);
+        log::warn!("{} 5", MARKER);
+
+        // the callback can change the destination while it runs
+        ufo_log_to_callback(seen as *const _ as *mut c_void, record_once);
+        log::error!("{} 6", MARKER);
+        assert!(matches!(*LOGGER.sink.read().unwrap(), LogSink::Stderr));
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(
+            vec![
+                format!("{} 1", MARKER),
+                format!("{} 4", MARKER),
+                format!("{} 6", MARKER)
+            ],
+            *seen
+        );
+    }
+}
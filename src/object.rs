@@ -1,79 +1,1013 @@
 use std::fmt::Debug;
-use std::sync::RwLockWriteGuard;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, RwLockWriteGuard, Weak,
+};
 
 use anyhow::Result;
+use crossbeam::{channel, sync::WaitGroup};
 
 use libc::c_void;
 use ufo_core::{UfoObject, WrappedUfoObject};
 
 use super::*;
 
+struct BodyGeometry {
+    body: *mut u8,
+    stride: usize,
+    element_ct: usize,
+    chunk_elements: usize,
+    read_only: bool,
+}
+
+impl BodyGeometry {
+    fn of(ufo: &UfoObject) -> Self {
+        BodyGeometry {
+            body: ufo.body_ptr().cast(),
+            stride: ufo.config.stride().alignment_quantum().bytes,
+            element_ct: ufo.config.element_ct().total().elements,
+            chunk_elements: ufo
+                .config
+                .elements_loaded_at_once()
+                .alignment_quantum()
+                .elements,
+            read_only: ufo.config.read_only(),
+        }
+    }
+
+    fn element(&self, index: usize) -> *mut u8 {
+        self.body.wrapping_add(index * self.stride)
+    }
+
+    /// true if `len` bytes from `offset` all lie within the body
+    fn contains_bytes(&self, offset: usize, len: usize) -> bool {
+        offset
+            .checked_add(len)
+            .is_some_and(|end| end <= self.element_ct * self.stride)
+    }
+
+    /// true if `count` elements from `start` all lie within the body
+    fn contains(&self, start: usize, count: usize) -> bool {
+        start
+            .checked_add(count)
+            .is_some_and(|end| end <= self.element_ct)
+    }
+
+    /// Read one byte from each chunk covering elements `start..end`, which
+    /// must be in bounds. The UFO must not be locked, the faults need it.
+    fn touch(&self, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+        let first_chunk = start / self.chunk_elements;
+        let last_chunk = (end - 1) / self.chunk_elements;
+        for chunk in first_chunk..=last_chunk {
+            let index = (chunk * self.chunk_elements).max(start);
+            unsafe { std::ptr::read_volatile(self.element(index)) };
+        }
+    }
+}
+
+/// A ufo_hint_willneed waiting for the core's hint thread
+pub(crate) struct Hint {
+    obj: UfoCObj,
+    start: usize,
+    end: usize,
+}
+
+/// The core's hint thread, which exits once the core drops its sender
+pub(crate) fn run_hints(hints: channel::Receiver<Hint>) {
+    for hint in hints {
+        let _ = UfoObj::wrap(hint.obj).prefault_hint(hint.start, hint.end);
+    }
+}
+
+/// A copy of the header taken before a reset, written back once it finishes.
+/// Reset drops the header pages too, so both copies fault and must run with
+/// the UFO unlocked; the Touching keeps the header mapped in between. While
+/// one exists ufo_header_ptr refuses to hand out the header.
+struct SavedHeader {
+    header: usize,
+    bytes: Vec<u8>,
+    restores: Arc<AtomicUsize>,
+    _touching: Touching,
+}
+
+impl SavedHeader {
+    /// None when there is no header to keep
+    fn save(obj: &UfoObj) -> Result<Option<Self>, UfoErrorCode> {
+        let (_, core) = obj.owned_ufo()?;
+        let (header, len, restores, touching) = {
+            let ufo = obj.live_ufo(|ufo| ufo.read().ok())?;
+            let len = ufo.config.header_size().bytes;
+            if len == 0 {
+                return Ok(None);
+            }
+            let restores = core
+                .data_map
+                .read()
+                .expect("can't lock map")
+                .get(&ufo.id)
+                .map(|params| params.header_restores.clone())
+                .ok_or_else(|| fail(UfoErrorCode::NullHandle, "the UFO has been freed"))?;
+            let touching = core.touch(ufo.id).ok_or_else(|| {
+                fail(
+                    UfoErrorCode::CoreShutdown,
+                    "the UFO core has been shut down",
+                )
+            })?;
+            restores.fetch_add(1, Ordering::AcqRel);
+            (ufo.header_ptr() as usize, len, restores, touching)
+        };
+        let bytes = unsafe { std::slice::from_raw_parts(header as *const u8, len) }.to_vec();
+        Ok(Some(SavedHeader {
+            header,
+            bytes,
+            restores,
+            _touching: touching,
+        }))
+    }
+
+    /// Call once the reset has finished
+    fn restore(self) {
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.bytes.as_ptr(),
+                self.header as *mut u8,
+                self.bytes.len(),
+            )
+        };
+    }
+}
+
+impl Drop for SavedHeader {
+    fn drop(&mut self) {
+        self.restores.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// An async reset or free for the core's reaper thread
+pub(crate) struct Deferred {
+    wait: WaitGroup,
+    header: Option<SavedHeader>,
+}
+
+impl Deferred {
+    /// Wait for the core, then put back the header a reset saved
+    pub(crate) fn finish(self) {
+        self.wait.wait();
+        if let Some(header) = self.header {
+            header.restore();
+        }
+    }
+}
+
+pub(crate) fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Where the data for an element currently lives
+#[repr(C)]
+pub enum UfoElementState {
+    Error = -1,
+    Resident = 0,
+    WrittenBack = 1,
+    Unloaded = 2,
+}
+
 #[repr(C)]
 pub struct UfoObj {
     ptr: *mut c_void,
 }
 
-opaque_c_type!(UfoObj, WrappedUfoObject);
+/// Totals for one UFO since it was created
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UfoObjStats {
+    pub populate_calls: u64,
+    /// calls that returned nonzero, included in populate_calls
+    pub populate_failures: u64,
+    pub populate_bytes: u64,
+    /// wall time spent in the populate function
+    pub populate_nanos: u64,
+}
+
+/// A UFO plus the core that owns it, so calls can tell a dead core from a dead object
+#[derive(Clone)]
+pub(crate) struct UfoCObj {
+    pub(crate) ufo: WrappedUfoObject,
+    pub(crate) core: Weak<UfoCCore>,
+}
+
+opaque_c_type!(UfoObj, UfoCObj);
 
 impl UfoObj {
-    fn with_ufo<F, T, E>(&self, f: F) -> Option<T>
+    pub(crate) fn new(ufo: WrappedUfoObject, core: &Arc<UfoCCore>) -> Self {
+        Self::wrap(UfoCObj {
+            ufo,
+            core: Arc::downgrade(core),
+        })
+    }
+
+    /// The UFO and its core, while the core is alive. The UFO may already have
+    /// been freed through another handle; live_ufo checks that too.
+    fn owned_ufo(&self) -> Result<(&WrappedUfoObject, Arc<UfoCCore>), UfoErrorCode> {
+        let obj = self
+            .deref()
+            .ok_or_else(|| fail(UfoErrorCode::NullHandle, "null UFO handle"))?;
+        match obj.core.upgrade() {
+            Some(core) if !core.is_shut_down() && !core.is_forked() => Ok((&obj.ufo, core)),
+            _ => Err(fail(
+                UfoErrorCode::CoreShutdown,
+                "the UFO core has been shut down",
+            )),
+        }
+    }
+
+    /// Lock the UFO with `lock` and check no handle has freed it. start_free
+    /// removes the id under the write lock, so the body stays mapped while the
+    /// returned guard is held.
+    fn live_ufo<'a, G, L>(&'a self, lock: L) -> Result<G, UfoErrorCode>
+    where
+        L: FnOnce(&'a WrappedUfoObject) -> Option<G>,
+        G: std::ops::Deref<Target = UfoObject>,
+    {
+        let (ufo, core) = self.owned_ufo()?;
+        let locked =
+            lock(ufo).ok_or_else(|| fail(UfoErrorCode::LockPoisoned, "UFO lock is poisoned"))?;
+        if !core.owns(locked.id) {
+            return Err(fail(UfoErrorCode::NullHandle, "the UFO has been freed"));
+        }
+        Ok(locked)
+    }
+
+    fn with_ufo<F, T, E>(&self, f: F) -> Result<T, UfoErrorCode>
     where
         F: FnOnce(RwLockWriteGuard<UfoObject>) -> Result<T, E>,
         E: Debug,
     {
-        self.deref().map(|ufo| {
-            let locked_ufo = ufo.write().expect("unable to lock UFO");
-            f(locked_ufo).expect("Function call failed")
+        let locked_ufo = self.live_ufo(|ufo| ufo.write().ok())?;
+        f(locked_ufo).map_err(|e| fail(UfoErrorCode::ChannelBroken, format!("{:?}", e)))
+    }
+
+    /// Snapshot the body layout under one read lock
+    fn body_geometry(&self) -> Result<BodyGeometry, UfoErrorCode> {
+        self.live_ufo(|ufo| ufo.read().ok())
+            .map(|ufo| BodyGeometry::of(&ufo))
+    }
+
+    /// body_geometry for calls that are about to touch the body
+    fn touchable_geometry(&self) -> Result<BodyGeometry, UfoErrorCode> {
+        if crate::populate::in_populate() {
+            log::error!("UFO memory accessed from inside a populate callout, refusing to deadlock");
+            return Err(fail(
+                UfoErrorCode::ReentrantPopulate,
+                "cannot touch UFO memory from inside a populate callout",
+            ));
+        }
+        self.body_geometry()
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ufo_element_ptr(&self, index: usize) -> *mut std::ffi::c_void {
+        catch_error(|| {
+            self.body_geometry()
+                .ok()
+                .filter(|g| index < g.element_ct)
+                .map(|g| g.element(index).cast())
+                .unwrap_or_else(std::ptr::null_mut)
+        })
+        .unwrap_or(std::ptr::null_mut())
+    }
+
+    /// Fill `out` with pointers to elements `start..start+count`, locking the UFO once
+    ///
+    /// # Safety
+    ///
+    /// `out` must be valid for writing `count` pointers.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_element_ptrs(
+        &self,
+        start: usize,
+        count: usize,
+        out: *mut *mut std::ffi::c_void,
+    ) -> UfoErrorCode {
+        catch_error(|| match self.body_geometry() {
+            Err(e) => e,
+            Ok(_) if out.is_null() => fail(UfoErrorCode::NullHandle, "null out pointer"),
+            Ok(g) if !g.contains(start, count) => {
+                fail(UfoErrorCode::OutOfBounds, "element range outside the body")
+            }
+            Ok(g) => {
+                let out = std::slice::from_raw_parts_mut(out, count);
+                for (i, slot) in out.iter_mut().enumerate() {
+                    *slot = g.element(start + i).cast();
+                }
+                UfoErrorCode::Ok
+            }
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// Populate every chunk covering elements `start..end` before returning.
+    /// Chunks that are already resident are not populated again.
+    #[no_mangle]
+    pub extern "C" fn ufo_prefault(&self, start: usize, end: usize) -> UfoErrorCode {
+        catch_error(|| match self.touchable_geometry() {
+            Err(e) => e,
+            Ok(g) if start > end || !g.contains(start, end - start) => {
+                fail(UfoErrorCode::OutOfBounds, "element range outside the body")
+            }
+            Ok(g) => {
+                g.touch(start, end);
+                UfoErrorCode::Ok
+            }
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// Queue the chunks covering elements `start..end` to be populated by the
+    /// core's hint thread and return at once. Advisory only, like
+    /// MADV_WILLNEED: the hint is dropped if the queue is full or the UFO is
+    /// freed first, and nothing waits for it. Safe to call from a populate callout.
+    #[no_mangle]
+    pub extern "C" fn ufo_hint_willneed(&self, start: usize, end: usize) -> UfoErrorCode {
+        catch_error(|| match self.body_geometry() {
+            Err(e) => e,
+            Ok(g) if start > end || !g.contains(start, end - start) => {
+                fail(UfoErrorCode::OutOfBounds, "element range outside the body")
+            }
+            Ok(_) if start == end => UfoErrorCode::Ok,
+            Ok(_) => {
+                if let Some(obj) = self.deref() {
+                    let hint = Hint {
+                        obj: obj.clone(),
+                        start,
+                        end,
+                    };
+                    if let Some(core) = obj.core.upgrade() {
+                        if core.hints.try_send(hint).is_err() {
+                            log::debug!("hint queue full, dropping hint for {}..{}", start, end);
+                        }
+                    }
+                }
+                UfoErrorCode::Ok
+            }
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// Mark the chunks covering elements `start..end` as the first to evict.
+    /// Advisory like ufo_hint_willneed, but not supported yet: reordering
+    /// eviction needs ufo_core's chunk queue, so once the range is checked this
+    /// fails with Unsupported and changes nothing. Callers may ignore that.
+    #[no_mangle]
+    pub extern "C" fn ufo_hint_dontneed(&self, start: usize, end: usize) -> UfoErrorCode {
+        catch_error(|| match self.body_geometry() {
+            Err(e) => e,
+            Ok(g) if start > end || !g.contains(start, end - start) => {
+                fail(UfoErrorCode::OutOfBounds, "element range outside the body")
+            }
+            Ok(_) => fail(
+                UfoErrorCode::Unsupported,
+                "ufo_core cannot reorder its eviction queue",
+            ),
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// ufo_prefault for the hint thread. The UFO is marked as touched before
+    /// the lock is dropped, so start_free and shutdown wait for the touch to
+    /// finish instead of unmapping the body under it.
+    fn prefault_hint(&self, start: usize, end: usize) -> Result<(), UfoErrorCode> {
+        let (_, core) = self.owned_ufo()?;
+        let (g, _touching) = {
+            let ufo = self.live_ufo(|ufo| ufo.read().ok())?;
+            let touching = core.touch(ufo.id).ok_or(UfoErrorCode::CoreShutdown)?;
+            (BodyGeometry::of(&ufo), touching)
+        };
+        g.touch(start, end);
+        Ok(())
+    }
+
+    /// Report whether an element is resident without touching the body.
+    /// Writeback state lives inside ufo_core, so anything not resident is
+    /// reported as `Unloaded` for now.
+    #[no_mangle]
+    pub extern "C" fn ufo_element_state(&self, index: usize) -> UfoElementState {
+        catch_error(|| {
+            self.body_geometry()
+                .ok()
+                .filter(|g| index < g.element_ct)
+                .map(|g| {
+                    let page_size = page_size();
+                    let addr = g.element(index) as usize;
+                    let page = (addr - addr % page_size) as *mut c_void;
+                    let mut resident: libc::c_uchar = 0;
+                    match unsafe { libc::mincore(page, page_size, &mut resident) } {
+                        0 if resident & 1 == 1 => UfoElementState::Resident,
+                        0 => UfoElementState::Unloaded,
+                        _ => UfoElementState::Error,
+                    }
+                })
+                .unwrap_or(UfoElementState::Error)
+        })
+        .unwrap_or(UfoElementState::Error)
+    }
+
+    /// Bytes of this UFO's body currently resident in memory, or 0 for an invalid
+    /// handle. Evicted chunks are dropped from memory, so this counts only live data.
+    #[no_mangle]
+    pub extern "C" fn ufo_resident_bytes(&self) -> usize {
+        const PAGES_PER_CALL: usize = 1 << 16;
+
+        catch_error(|| {
+            self.body_geometry()
+                .map(|g| {
+                    let page_size = page_size();
+                    let pages = (g.element_ct * g.stride).div_ceil(page_size);
+                    let mut residency = vec![0 as libc::c_uchar; pages.min(PAGES_PER_CALL)];
+                    let mut resident_pages = 0;
+
+                    for first in (0..pages).step_by(PAGES_PER_CALL) {
+                        let ct = (pages - first).min(PAGES_PER_CALL);
+                        let addr = g.body.wrapping_add(first * page_size).cast();
+                        let ret =
+                            unsafe { libc::mincore(addr, ct * page_size, residency.as_mut_ptr()) };
+                        assert_eq!(0, ret, "mincore failed on a UFO body");
+                        resident_pages += residency[..ct].iter().filter(|r| *r & 1 == 1).count();
+                    }
+                    resident_pages * page_size
+                })
+                .unwrap_or(0)
+        })
+        .unwrap_or(0)
+    }
+
+    /// Copy `len` bytes starting `offset_bytes` into the body out to `dst`,
+    /// populating as needed. The body pointer never escapes to the caller.
+    ///
+    /// # Safety
+    ///
+    /// `dst` must be valid for writing `len` bytes, and may be NULL only when
+    /// `len` is 0.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_read(
+        &self,
+        offset_bytes: usize,
+        len: usize,
+        dst: *mut u8,
+    ) -> UfoErrorCode {
+        catch_error(|| match self.touchable_geometry() {
+            Err(e) => e,
+            Ok(_) if dst.is_null() && len > 0 => {
+                fail(UfoErrorCode::NullHandle, "null destination pointer")
+            }
+            Ok(g) if !g.contains_bytes(offset_bytes, len) => {
+                fail(UfoErrorCode::OutOfBounds, "byte range outside the body")
+            }
+            Ok(g) => {
+                std::ptr::copy_nonoverlapping(g.body.add(offset_bytes), dst, len);
+                UfoErrorCode::Ok
+            }
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// Copy `len` bytes from `src` into the body at `offset_bytes`. The
+    /// modified chunks are written back on eviction like any other write.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reading `len` bytes, and may be NULL only when
+    /// `len` is 0.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_write(
+        &self,
+        offset_bytes: usize,
+        len: usize,
+        src: *const u8,
+    ) -> UfoErrorCode {
+        catch_error(|| match self.touchable_geometry() {
+            Err(e) => e,
+            Ok(_) if src.is_null() && len > 0 => {
+                fail(UfoErrorCode::NullHandle, "null source pointer")
+            }
+            Ok(g) if !g.contains_bytes(offset_bytes, len) => {
+                fail(UfoErrorCode::OutOfBounds, "byte range outside the body")
+            }
+            Ok(g) if g.read_only => fail(UfoErrorCode::ReadOnly, "write to a read-only UFO"),
+            Ok(g) => {
+                std::ptr::copy_nonoverlapping(src, g.body.add(offset_bytes), len);
+                UfoErrorCode::Ok
+            }
         })
+        .unwrap_or(UfoErrorCode::Panic)
     }
 
+    /// Drop all loaded and written back data. The header is copied first and
+    /// written back once the reset finishes. Until then other threads see a
+    /// zeroed header, ufo_header_ptr fails with ResetPending, and writes through
+    /// a header pointer fetched earlier are overwritten by the restore.
+    ///
+    /// # Safety
+    ///
+    /// No other thread may be using the body while it resets; what it reads
+    /// or writes there can be dropped at any point during the call.
     #[no_mangle]
-    pub unsafe extern "C" fn ufo_reset(&mut self) -> i32 {
-        std::panic::catch_unwind(|| {
+    pub unsafe extern "C" fn ufo_reset(&mut self) -> UfoErrorCode {
+        catch_error(|| {
+            self.start_reset()
+                .map(|op| op.finish())
+                .map_or_else(|e| e, |()| UfoErrorCode::Ok)
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// Like ufo_reset, but the header is zeroed too
+    ///
+    /// # Safety
+    ///
+    /// As for ufo_reset, and the same goes for the header.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_reset_with_header(&mut self) -> UfoErrorCode {
+        catch_error(|| {
             self.with_ufo(|mut ufo| ufo.reset())
                 .map(|w| w.wait())
-                .map(|()| 0)
-                .unwrap_or(-1)
+                .map_or_else(|e| e, |()| UfoErrorCode::Ok)
         })
-        .unwrap_or(-1)
+        .unwrap_or(UfoErrorCode::Panic)
     }
 
+    /// Queue a reset and return without waiting for it; ufo_core_drain waits
+    /// for it to finish. The header is kept as in ufo_reset, but is restored by
+    /// the core's reaper thread: until the drain returns it reads as zeros,
+    /// ufo_header_ptr fails with ResetPending, and anything written through a
+    /// header pointer fetched earlier is overwritten when it is restored.
+    #[no_mangle]
+    pub extern "C" fn ufo_reset_async(&self) -> UfoErrorCode {
+        catch_error(|| {
+            self.start_reset()
+                .map(|op| self.defer(op))
+                .map_or_else(|e| e, |()| UfoErrorCode::Ok)
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// Save the header, then send the reset to the core
+    fn start_reset(&self) -> Result<Deferred, UfoErrorCode> {
+        let header = SavedHeader::save(self)?;
+        self.with_ufo(|mut ufo| ufo.reset())
+            .map(|wait| Deferred { wait, header })
+    }
+
+    /// NULL while a reset is restoring the header, see ufo_reset_async
     #[no_mangle]
     pub extern "C" fn ufo_header_ptr(&self) -> *mut std::ffi::c_void {
-        std::panic::catch_unwind(|| {
-            self.with_ufo(|ufo| Ok::<*mut c_void, ()>(ufo.header_ptr()))
-                .unwrap_or_else(|| std::ptr::null_mut())
+        catch_error(|| {
+            let core = self.deref().and_then(|obj| obj.core.upgrade());
+            self.with_ufo(|ufo| Ok::<_, ()>((ufo.header_ptr(), ufo.id)))
+                .and_then(|(header, id)| {
+                    let restoring = core.is_some_and(|core| {
+                        let map = core.data_map.read().expect("can't lock map");
+                        map.get(&id).is_some_and(|params| {
+                            params.header_restores.load(Ordering::Acquire) > 0
+                        })
+                    });
+                    if restoring {
+                        Err(fail(
+                            UfoErrorCode::ResetPending,
+                            "the header is being restored after a reset",
+                        ))
+                    } else {
+                        Ok(header)
+                    }
+                })
+                .unwrap_or(std::ptr::null_mut())
         })
-        .unwrap_or_else(|_| std::ptr::null_mut())
+        .unwrap_or(std::ptr::null_mut())
     }
 
     #[no_mangle]
     pub extern "C" fn ufo_body_ptr(&self) -> *mut std::ffi::c_void {
-        std::panic::catch_unwind(|| {
+        catch_error(|| {
             self.with_ufo(|ufo| Ok::<*mut c_void, ()>(ufo.body_ptr()))
-                .unwrap_or_else(|| std::ptr::null_mut())
+                .unwrap_or_else(|_| std::ptr::null_mut())
         })
         .unwrap_or_else(|_| std::ptr::null_mut())
     }
 
+    /// The largest power of two the body pointer is aligned to, 0 on error.
+    /// Always at least the page size, since bodies start on a page.
+    #[no_mangle]
+    pub extern "C" fn ufo_body_alignment(&self) -> usize {
+        let body = self.ufo_body_ptr() as usize;
+        if body == 0 {
+            return 0;
+        }
+        1 << body.trailing_zeros()
+    }
+
+    /// Free the UFO and release this handle. Freeing through a second handle to
+    /// the same UFO (see ufo_clone) is a no-op.
+    #[no_mangle]
+    pub extern "C" fn ufo_free(self) -> UfoErrorCode {
+        catch_error(|| {
+            self.start_free()
+                .map(|wait| wait.map(|w| w.wait()))
+                .map_or_else(|e| e, |_| UfoErrorCode::Ok)
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// Queue the free and return without waiting for it; ufo_core_drain waits
+    /// for it to finish. The body must not be touched after this call, the
+    /// mapping goes away whenever the core gets to it.
+    #[no_mangle]
+    pub extern "C" fn ufo_free_async(self) -> UfoErrorCode {
+        catch_error(|| {
+            self.start_free()
+                .map(|wait| wait.map(|wait| self.defer(Deferred { wait, header: None })))
+                .map_or_else(|e| e, |_| UfoErrorCode::Ok)
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// Send the free to the core, or None if another handle already freed this UFO
+    fn start_free(&self) -> Result<Option<WaitGroup>, UfoErrorCode> {
+        let (ufo, core) = self.owned_ufo()?;
+        let lock = || {
+            ufo.write()
+                .map_err(|_| fail(UfoErrorCode::LockPoisoned, "UFO lock is poisoned"))
+        };
+        let id = {
+            let ufo = lock()?;
+            // removed under the UFO lock so exactly one handle sends the free
+            let mut map = core.data_map.write().expect("can't lock map");
+            if map.remove(&ufo.id).is_none() {
+                return Ok(None);
+            }
+            ufo.id
+        };
+        // hints and header restores fault, which needs the UFO lock, so wait with it released
+        core.wait_for_touches(|touched| touched == id);
+        core.ufos_freed.fetch_add(1, Ordering::Relaxed);
+        lock()?
+            .free()
+            .map(Some)
+            .map_err(|e| fail(UfoErrorCode::ChannelBroken, format!("{:?}", e)))
+    }
+
+    /// Hand an operation to the core so ufo_core_drain can block on it
+    fn defer(&self, op: Deferred) {
+        match self.deref().and_then(|obj| obj.core.upgrade()) {
+            Some(core) => core.defer(op),
+            None => op.finish(),
+        }
+    }
+
+    /// Another handle to the same UFO, usable from any thread. Each handle must be
+    /// given to ufo_release or ufo_free, and the UFO itself lives until the first
+    /// ufo_free or core shutdown.
     #[no_mangle]
-    pub extern "C" fn ufo_free(self) {
-        std::panic::catch_unwind(|| {
-            self.deref()
-                .map(|ufo| {
-                    ufo.write()
-                        .expect("unable to lock UFO")
-                        .free()
-                        .expect("unable to free UFO")
+    pub extern "C" fn ufo_clone(&self) -> UfoObj {
+        self.deref()
+            .map(|obj| UfoObj::wrap(obj.clone()))
+            .unwrap_or_else(UfoObj::none)
+    }
+
+    /// Drop this handle without freeing the UFO, for handles obtained from
+    /// ufo_get_by_address, ufo_get_by_id, or ufo_lookup_address
+    #[no_mangle]
+    pub extern "C" fn ufo_release(self) {
+        drop(self)
+    }
+
+    /// The id to hand to ufo_get_by_id to recover this UFO later
+    ///
+    /// # Safety
+    ///
+    /// `id` must be NULL or valid for writing a u64.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_id(&self, id: *mut u64) -> UfoErrorCode {
+        catch_error(|| {
+            if id.is_null() {
+                return fail(UfoErrorCode::NullHandle, "null id pointer");
+            }
+            self.with_ufo(|ufo| Ok::<u64, ()>(ufo.id.0))
+                .map(|ufo_id| *id = ufo_id)
+                .map_or_else(|e| e, |()| UfoErrorCode::Ok)
+        })
+        .unwrap_or(UfoErrorCode::Panic)
+    }
+
+    /// Copy this UFO's populate totals into `out`. Counters are updated by the
+    /// populate thread without locking, so totals may lag by a call in flight.
+    ///
+    /// # Safety
+    ///
+    /// `out` must be NULL or valid for writing a UfoObjStats.
+    #[no_mangle]
+    pub unsafe extern "C" fn ufo_stats(&self, out: *mut UfoObjStats) -> UfoErrorCode {
+        catch_error(|| {
+            if out.is_null() {
+                return fail(UfoErrorCode::NullHandle, "null stats pointer");
+            }
+            let core = self.deref().and_then(|obj| obj.core.upgrade());
+            self.with_ufo(|ufo| Ok::<_, ()>(ufo.id))
+                .and_then(|id| {
+                    core.and_then(|core| {
+                        let map = core.data_map.read().expect("can't lock map");
+                        map.get(&id).map(|params| params.stats.snapshot())
+                    })
+                    .ok_or_else(|| fail(UfoErrorCode::NullHandle, "the UFO has been freed"))
                 })
-                .map(|w| w.wait())
-                .unwrap_or(())
+                .map(|stats| *out = stats)
+                .map_or_else(|e| e, |()| UfoErrorCode::Ok)
         })
-        .unwrap_or(())
+        .unwrap_or(UfoErrorCode::Panic)
     }
 
     #[no_mangle]
     pub extern "C" fn ufo_is_error(&self) -> bool {
         self.deref().is_none()
     }
+
+    /// True if the UFO can still be used: no handle has freed it, its core is
+    /// running and not left behind by a fork, and its lock is not poisoned
+    #[no_mangle]
+    pub extern "C" fn ufo_is_valid(&self) -> bool {
+        catch_error(|| self.live_ufo(|ufo| ufo.read().ok()).is_ok()).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    fn geometry(element_ct: usize) -> BodyGeometry {
+        BodyGeometry {
+            body: 0x10000 as *mut u8,
+            stride: 8,
+            element_ct,
+            chunk_elements: 512,
+            read_only: false,
+        }
+    }
+
+    #[test]
+    fn body_geometry_bounds() {
+        let g = geometry(100);
+        assert_eq!(0x10000 + 8 * 99, g.element(99) as usize);
+        assert!(g.contains(0, 100));
+        assert!(g.contains(100, 0));
+        assert!(!g.contains(99, 2));
+        assert!(!g.contains(usize::MAX, 2));
+        assert!(g.contains_bytes(0, 800));
+        assert!(g.contains_bytes(799, 1));
+        assert!(!g.contains_bytes(800, 1));
+        assert!(!g.contains_bytes(1, usize::MAX));
+    }
+
+    #[test]
+    fn element_ptr() {
+        let core = test_core();
+        let obj = core.ufo_new_object(&u64_params(0, 1000));
+        let body = obj.ufo_body_ptr() as usize;
+
+        assert_eq!(body, obj.ufo_element_ptr(0) as usize);
+        assert_eq!(body + 8 * 999, obj.ufo_element_ptr(999) as usize);
+        assert!(obj.ufo_element_ptr(1000).is_null());
+        // the tail chunk stops at the last element
+        assert_eq!(999, unsafe { *obj.ufo_element_ptr(999).cast::<u64>() });
+
+        let mut ptrs = [std::ptr::null_mut(); 3];
+        assert_eq!(UfoErrorCode::Ok, unsafe {
+            obj.ufo_element_ptrs(997, 3, ptrs.as_mut_ptr())
+        });
+        assert_eq!(body + 8 * 997, ptrs[0] as usize);
+        assert_eq!(body + 8 * 999, ptrs[2] as usize);
+        assert_eq!(UfoErrorCode::OutOfBounds, unsafe {
+            obj.ufo_element_ptrs(998, 3, ptrs.as_mut_ptr())
+        });
+        assert_eq!(UfoErrorCode::NullHandle, unsafe {
+            obj.ufo_element_ptrs(0, 1, std::ptr::null_mut())
+        });
+
+        let other = obj.ufo_clone();
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        assert!(other.ufo_element_ptr(0).is_null());
+        assert_eq!(UfoErrorCode::NullHandle, unsafe {
+            other.ufo_element_ptrs(0, 1, ptrs.as_mut_ptr())
+        });
+        other.ufo_release();
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn id_round_trip() {
+        let core = test_core();
+        let obj = core.ufo_new_object(&u64_params(0, 10));
+        let mut id = 0;
+        assert_eq!(UfoErrorCode::Ok, unsafe { obj.ufo_id(&mut id) });
+        let found = core.ufo_get_by_id(id);
+        assert_eq!(obj.ufo_body_ptr(), found.ufo_body_ptr());
+        found.ufo_release();
+        assert_eq!(UfoErrorCode::NullHandle, unsafe {
+            obj.ufo_id(std::ptr::null_mut())
+        });
+
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        assert!(core.ufo_get_by_id(id).ufo_is_error());
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn freed_clone_is_refused() {
+        let core = test_core();
+        let obj = core.ufo_new_object(&u64_params(64, 10_000));
+        let other = obj.ufo_clone();
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+
+        assert!(other.ufo_body_ptr().is_null());
+        assert!(other.ufo_header_ptr().is_null());
+        assert!(other.ufo_element_ptr(0).is_null());
+        assert_eq!(0, other.ufo_resident_bytes());
+        assert_eq!(UfoErrorCode::NullHandle, other.ufo_prefault(0, 10));
+        assert_eq!(UfoErrorCode::NullHandle, other.ufo_hint_willneed(0, 10));
+        let mut byte = 0u8;
+        assert_eq!(UfoErrorCode::NullHandle, unsafe {
+            other.ufo_read(0, 1, &mut byte)
+        });
+        assert_eq!(UfoErrorCode::NullHandle, unsafe {
+            other.ufo_write(0, 1, &byte)
+        });
+        // a second free is still a no-op
+        assert_eq!(UfoErrorCode::Ok, other.ufo_free());
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn read_and_write() {
+        let core = test_core();
+        let obj = core.ufo_new_object(&u64_params(0, 1000));
+        let mut value = 0u64;
+        let dst = (&mut value as *mut u64).cast();
+        assert_eq!(UfoErrorCode::Ok, unsafe { obj.ufo_read(8 * 999, 8, dst) });
+        assert_eq!(999, value);
+        assert_eq!(UfoErrorCode::OutOfBounds, unsafe {
+            obj.ufo_read(8 * 999, 9, dst)
+        });
+
+        let seven: u64 = 7;
+        let src = (&seven as *const u64).cast();
+        assert_eq!(UfoErrorCode::Ok, unsafe { obj.ufo_write(0, 8, src) });
+        assert_eq!(7, unsafe { *obj.ufo_element_ptr(0).cast::<u64>() });
+
+        assert_eq!(UfoErrorCode::NullHandle, unsafe {
+            obj.ufo_read(0, 8, std::ptr::null_mut())
+        });
+        assert_eq!(UfoErrorCode::NullHandle, unsafe {
+            obj.ufo_write(0, 8, std::ptr::null())
+        });
+        // nothing to copy, so nothing to point at
+        assert_eq!(UfoErrorCode::Ok, unsafe {
+            obj.ufo_read(0, 0, std::ptr::null_mut())
+        });
+
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn validity() {
+        let core = test_core();
+        assert!(!UfoObj::none().ufo_is_valid());
+
+        let obj = core.ufo_new_object(&u64_params(0, 10));
+        let other = obj.ufo_clone();
+        assert!(obj.ufo_is_valid() && other.ufo_is_valid());
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        assert!(!other.ufo_is_valid());
+        other.ufo_release();
+
+        let obj = core.ufo_new_object(&u64_params(0, 10));
+        assert_eq!(0, core.ufo_core_shutdown());
+        assert!(!obj.ufo_is_valid());
+        obj.ufo_release();
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn willneed_populates_without_a_touch() {
+        let core = test_core();
+        let obj = core.ufo_new_object(&u64_params(0, 1 << 20));
+        let hinted = (1 << 19)..(1 << 19) + 1000;
+        assert_eq!(
+            UfoErrorCode::Ok,
+            obj.ufo_hint_willneed(hinted.start, hinted.end)
+        );
+
+        let resident = |i| matches!(obj.ufo_element_state(i), UfoElementState::Resident);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while !hinted.clone().all(resident) && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(hinted.clone().all(resident));
+
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn dontneed_is_refused() {
+        let core = test_core();
+        let obj = core.ufo_new_object(&u64_params(0, 1000));
+        assert_eq!(UfoErrorCode::Unsupported, obj.ufo_hint_dontneed(0, 1000));
+        assert_eq!(UfoErrorCode::OutOfBounds, obj.ufo_hint_dontneed(0, 1001));
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn hints_racing_free() {
+        let core = test_core();
+        for _ in 0..20 {
+            let obj = core.ufo_new_object(&u64_params(0, 100_000));
+            // more hints than the queue holds, the extras are dropped
+            for i in 0..100 {
+                assert_eq!(
+                    UfoErrorCode::Ok,
+                    obj.ufo_hint_willneed(i * 1000, (i + 1) * 1000)
+                );
+            }
+            assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        }
+        assert_eq!(0, core.ufo_core_shutdown());
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn reset_keeps_header() {
+        let core = test_core();
+        let mut obj = core.ufo_new_object(&u64_params(16, 10_000));
+        let header = obj.ufo_header_ptr().cast::<u8>();
+        unsafe { header.write_bytes(7, 16) };
+        let seven: u64 = 7;
+        assert_eq!(UfoErrorCode::Ok, unsafe {
+            obj.ufo_write(8, 8, (&seven as *const u64).cast())
+        });
+
+        assert_eq!(UfoErrorCode::Ok, unsafe { obj.ufo_reset() });
+        let header = obj.ufo_header_ptr().cast::<u8>();
+        assert_eq!(&[7; 16], unsafe { std::slice::from_raw_parts(header, 16) });
+        assert_eq!(1, unsafe { *obj.ufo_element_ptr(1).cast::<u64>() });
+
+        unsafe { header.write_bytes(9, 16) };
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_reset_async());
+        assert_eq!(UfoErrorCode::Ok, core.ufo_core_drain(0));
+        let header = obj.ufo_header_ptr().cast::<u8>();
+        assert_eq!(&[9; 16], unsafe { std::slice::from_raw_parts(header, 16) });
+
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        core.ufo_core_free();
+    }
+
+    #[test]
+    fn concurrent_handles() {
+        let core = test_core();
+        let obj = core.ufo_new_object(&u64_params(64, 100_000));
+
+        let threads: Vec<_> = (0..3)
+            .map(|role| {
+                let handle = SendObj(obj.ufo_clone());
+                std::thread::spawn(move || {
+                    let mut handle = handle.0;
+                    for i in 0..100 {
+                        match role {
+                            0 => {
+                                handle.ufo_body_ptr();
+                            }
+                            1 => {
+                                let ret = unsafe { handle.ufo_reset() };
+                                assert!(ret == UfoErrorCode::Ok || ret == UfoErrorCode::NullHandle);
+                            }
+                            _ if i == 50 => return handle.ufo_free(),
+                            _ => std::thread::yield_now(),
+                        }
+                    }
+                    handle.ufo_release();
+                    UfoErrorCode::Ok
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            assert_eq!(UfoErrorCode::Ok, thread.join().unwrap());
+        }
+        assert!(obj.ufo_body_ptr().is_null());
+        assert_eq!(UfoErrorCode::Ok, obj.ufo_free());
+        core.ufo_core_free();
+    }
 }
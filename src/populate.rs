@@ -0,0 +1,320 @@
+use std::{
+    cell::Cell,
+    fs::File,
+    os::unix::fs::FileExt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{UfoObjStats, UfoPopulateCallout, UfoPopulateCalloutV2, UfoPopulateData};
+
+const CANARY: u8 = 0xa5;
+const CANARY_LEN: usize = 64;
+
+thread_local! {
+    static IN_POPULATE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// True while this thread is inside a populate call. Touching UFO memory from
+/// here would fault back into the populate thread and hang forever.
+pub(crate) fn in_populate() -> bool {
+    IN_POPULATE.with(Cell::get)
+}
+
+/// Marks the current thread as populating until dropped
+pub(crate) struct PopulateGuard;
+
+impl PopulateGuard {
+    pub(crate) fn enter() -> Self {
+        IN_POPULATE.with(|p| p.set(true));
+        PopulateGuard
+    }
+}
+
+impl Drop for PopulateGuard {
+    fn drop(&mut self) {
+        IN_POPULATE.with(|p| p.set(false));
+    }
+}
+
+/// Running totals of one UFO's populate calls, updated without taking any lock
+#[derive(Default)]
+pub(crate) struct PopulateCounters {
+    calls: AtomicU64,
+    failures: AtomicU64,
+    bytes: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl PopulateCounters {
+    pub(crate) fn record(&self, bytes: usize, elapsed: Duration, ok: bool) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> UfoObjStats {
+        UfoObjStats {
+            populate_calls: self.calls.load(Ordering::Relaxed),
+            populate_failures: self.failures.load(Ordering::Relaxed),
+            populate_bytes: self.bytes.load(Ordering::Relaxed),
+            populate_nanos: self.nanos.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Read until `buffer` is full or the file ends, returning the bytes read
+fn read_full_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read_at(&mut buffer[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Where a UFO's chunk contents come from
+pub(crate) enum Populator {
+    Callout(UfoPopulateCallout),
+    CalloutV2(UfoPopulateCalloutV2),
+    /// Every element starts as zero bytes
+    Zero,
+    /// Every element starts as a copy of this stride-sized pattern
+    Constant(Vec<u8>),
+    /// The body is read from `file`, starting `offset` bytes in
+    File {
+        file: File,
+        offset: u64,
+    },
+    /// A Rust closure given the element range and the buffer to fill
+    #[cfg(feature = "rust-api")]
+    Closure(Box<dyn Fn(usize, usize, *mut libc::c_uchar) -> i32 + Send + Sync>),
+}
+
+impl Populator {
+    /// Fill elements `start..end` into `buffer`, returning non-zero on failure
+    pub(crate) fn populate(
+        &self,
+        data: UfoPopulateData,
+        stride: usize,
+        ufo_id: u64,
+        start: usize,
+        end: usize,
+        buffer: *mut libc::c_uchar,
+    ) -> i32 {
+        match self {
+            Populator::Callout(populate_fn) => populate_fn(data, start, end, buffer),
+            Populator::CalloutV2(populate_fn) => {
+                // end is already capped at element_ct for the tail chunk
+                populate_fn(data, start, end, buffer, (end - start) * stride, ufo_id)
+            }
+            Populator::Zero => {
+                // the core reuses its write buffer, so it can't be assumed zeroed
+                unsafe { std::ptr::write_bytes(buffer, 0, (end - start) * stride) };
+                0
+            }
+            Populator::Constant(pattern) => {
+                let buffer =
+                    unsafe { std::slice::from_raw_parts_mut(buffer, (end - start) * stride) };
+                buffer
+                    .chunks_exact_mut(stride)
+                    .for_each(|element| element.copy_from_slice(pattern));
+                0
+            }
+            Populator::File { file, offset } => {
+                let buffer =
+                    unsafe { std::slice::from_raw_parts_mut(buffer, (end - start) * stride) };
+                let file_offset = offset + (start * stride) as u64;
+                match read_full_at(file, buffer, file_offset) {
+                    Ok(read) => {
+                        // short only if the file shrank after the UFO was created
+                        buffer[read..].iter_mut().for_each(|b| *b = 0);
+                        0
+                    }
+                    Err(e) => {
+                        log::error!("populate read at offset {} failed: {}", file_offset, e);
+                        -1
+                    }
+                }
+            }
+            #[cfg(feature = "rust-api")]
+            Populator::Closure(populate) => populate(start, end, buffer),
+        }
+    }
+
+    /// Like populate, but through a scratch buffer followed by canary bytes, so a
+    /// callout that writes past the end of its chunk fails instead of corrupting memory
+    pub(crate) fn populate_checked(
+        &self,
+        data: UfoPopulateData,
+        stride: usize,
+        ufo_id: u64,
+        start: usize,
+        end: usize,
+        buffer: *mut libc::c_uchar,
+    ) -> i32 {
+        let len = (end - start) * stride;
+        let mut scratch = vec![CANARY; len + CANARY_LEN];
+        let ret = self.populate(data, stride, ufo_id, start, end, scratch.as_mut_ptr());
+
+        if scratch[len..].iter().any(|b| *b != CANARY) {
+            log::error!(
+                "populate for UFO {} elements {}..{} wrote past its {} byte buffer",
+                ufo_id,
+                start,
+                end,
+                len
+            );
+            return -1;
+        }
+        unsafe { std::ptr::copy_nonoverlapping(scratch.as_ptr(), buffer, len) };
+        ret
+    }
+
+    /// The original style callout, the only kind UfoParameters can describe
+    pub(crate) fn callout(&self) -> Option<UfoPopulateCallout> {
+        match self {
+            Populator::Callout(populate_fn) => Some(*populate_fn),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Writes one byte past the chunk it was asked for
+    extern "C" fn overrunning(
+        _data: UfoPopulateData,
+        start: usize,
+        end: usize,
+        buffer: *mut libc::c_uchar,
+    ) -> i32 {
+        unsafe { std::ptr::write_bytes(buffer, 1, (end - start) * 8 + 1) };
+        0
+    }
+
+    extern "C" fn well_behaved(
+        _data: UfoPopulateData,
+        start: usize,
+        end: usize,
+        buffer: *mut libc::c_uchar,
+    ) -> i32 {
+        unsafe { std::ptr::write_bytes(buffer, 1, (end - start) * 8) };
+        0
+    }
+
+    /// Records the buffer_len it is given in the usize `data` points at
+    extern "C" fn record_len(
+        data: UfoPopulateData,
+        _start: usize,
+        _end: usize,
+        _buffer: *mut libc::c_uchar,
+        buffer_len: usize,
+        _ufo_id: u64,
+    ) -> i32 {
+        unsafe { *(data as *mut usize) = buffer_len };
+        0
+    }
+
+    fn populate(populator: &Populator, stride: usize, start: usize, end: usize) -> Vec<u8> {
+        let mut buffer = vec![0xff; (end - start) * stride];
+        let ret = populator.populate(
+            std::ptr::null_mut(),
+            stride,
+            1,
+            start,
+            end,
+            buffer.as_mut_ptr(),
+        );
+        assert_eq!(0, ret);
+        buffer
+    }
+
+    #[test]
+    fn zero_and_constant() {
+        assert!(populate(&Populator::Zero, 4, 10, 20)
+            .iter()
+            .all(|b| *b == 0));
+        assert_eq!(
+            vec![1, 2, 1, 2, 1, 2],
+            populate(&Populator::Constant(vec![1, 2]), 2, 5, 8)
+        );
+    }
+
+    #[test]
+    fn file_reads_at_offset_and_zero_fills_past_the_end() {
+        let path = std::env::temp_dir().join(format!("ufo-populate-test-{}", std::process::id()));
+        File::create(&path)
+            .unwrap()
+            .write_all(&[1, 2, 3, 4, 5, 6])
+            .unwrap();
+        let populator = Populator::File {
+            file: File::open(&path).unwrap(),
+            offset: 1,
+        };
+        assert_eq!(vec![4, 5, 6, 0], populate(&populator, 2, 1, 3));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn v2_is_told_the_tail_chunk_length() {
+        // a 1000 element body ends partway through a 512 element chunk
+        let mut len = 0usize;
+        let mut buffer = vec![0u8; 488 * 8];
+        let ret = Populator::CalloutV2(record_len).populate(
+            &mut len as *mut usize as UfoPopulateData,
+            8,
+            1,
+            512,
+            1000,
+            buffer.as_mut_ptr(),
+        );
+        assert_eq!(0, ret);
+        assert_eq!(488 * 8, len);
+    }
+
+    #[test]
+    fn canaries_catch_an_overrun() {
+        let mut buffer = vec![0u8; 4 * 8 + 1];
+        let checked = |populator: &Populator, buffer: &mut Vec<u8>| {
+            populator.populate_checked(std::ptr::null_mut(), 8, 1, 0, 4, buffer.as_mut_ptr())
+        };
+
+        assert_eq!(-1, checked(&Populator::Callout(overrunning), &mut buffer));
+        // nothing reaches the real buffer when the callout misbehaves
+        assert!(buffer.iter().all(|b| *b == 0));
+
+        assert_eq!(0, checked(&Populator::Callout(well_behaved), &mut buffer));
+        assert!(buffer[..32].iter().all(|b| *b == 1));
+        assert_eq!(0, buffer[32]);
+    }
+
+    #[test]
+    fn counters() {
+        let counters = PopulateCounters::default();
+        counters.record(4096, Duration::from_nanos(10), true);
+        counters.record(100, Duration::from_nanos(5), false);
+        let stats = counters.snapshot();
+        assert_eq!(
+            (2, 1, 4196, 15),
+            (
+                stats.populate_calls,
+                stats.populate_failures,
+                stats.populate_bytes,
+                stats.populate_nanos
+            )
+        );
+    }
+}
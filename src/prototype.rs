@@ -1,6 +1,15 @@
+use std::fmt;
+
 pub type UfoPopulateData = *mut libc::c_void;
 pub type UfoPopulateCallout =
     extern "C" fn(UfoPopulateData, usize, usize, *mut libc::c_uchar) -> i32;
+/// Also told how many bytes it may write and which UFO is being populated
+pub type UfoPopulateCalloutV2 =
+    extern "C" fn(UfoPopulateData, usize, usize, *mut libc::c_uchar, usize, u64) -> i32;
+/// Fills the header of a newly allocated UFO, nonzero fails the allocation
+pub type UfoHeaderPopulateCallout =
+    extern "C" fn(UfoPopulateData, *mut libc::c_uchar, usize) -> i32;
+pub type UfoPopulateDataFinalizer = Option<extern "C" fn(UfoPopulateData)>;
 
 pub type UfoWritebackListenerData = *mut libc::c_void;
 pub type UfoWritebackListener =
@@ -14,7 +23,163 @@ pub struct UfoParameters {
     pub min_load_ct: usize,
     pub read_only: bool,
     pub populate_data: UfoPopulateData,
-    pub populate_fn: UfoPopulateCallout,
+    /// NULL when read back from a UFO that was not created with ufo_new_object
+    pub populate_fn: Option<UfoPopulateCallout>,
     pub writeback_listener_data: UfoWritebackListenerData,
     pub writeback_listener: UfoWritebackListener,
 }
+
+/// Why a set of parameters can't describe a UFO
+#[derive(Debug)]
+pub(crate) enum UfoConfigError {
+    ZeroStride,
+    SizeOverflow,
+    ChunkTooLarge { chunk_bytes: usize, budget: usize },
+    TooLarge { bytes: usize, cap: usize },
+}
+
+impl fmt::Display for UfoConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UfoConfigError::ZeroStride => write!(f, "element_size must not be 0"),
+            UfoConfigError::SizeOverflow => write!(f, "UFO size overflows usize"),
+            UfoConfigError::ChunkTooLarge {
+                chunk_bytes,
+                budget,
+            } => write!(
+                f,
+                "chunk size {} exceeds the memory budget of {} between the watermarks",
+                chunk_bytes, budget
+            ),
+            UfoConfigError::TooLarge { bytes, cap } => write!(
+                f,
+                "UFO of {} bytes exceeds the core's max_object_bytes of {}",
+                bytes, cap
+            ),
+        }
+    }
+}
+
+impl UfoParameters {
+    /// Catch the parameters that would divide by zero or wrap in the size math
+    pub(crate) fn validate(&self) -> Result<(), UfoConfigError> {
+        if self.element_size == 0 {
+            return Err(UfoConfigError::ZeroStride);
+        }
+
+        let page_size = crate::object::page_size();
+        self.element_size
+            .checked_mul(self.element_ct)
+            .and_then(|body| body.checked_add(self.header_size))
+            // header and body are each rounded up to whole pages
+            .and_then(|size| size.checked_add(2 * page_size))
+            .filter(|size| *size <= isize::MAX as usize)
+            .and(self.element_size.checked_mul(self.min_load_ct))
+            .map(|_| ())
+            .ok_or(UfoConfigError::SizeOverflow)
+    }
+}
+
+/// Fill the size fields of `params` from 64 bit values, failing rather than
+/// truncating when a value does not fit in this platform's size_t
+///
+/// # Safety
+///
+/// `params` must be NULL or valid for writing a UfoParameters.
+#[no_mangle]
+pub unsafe extern "C" fn ufo_parameters_set_sizes(
+    params: *mut UfoParameters,
+    header_size: u64,
+    element_size: u64,
+    element_ct: u64,
+    min_load_ct: u64,
+) -> crate::UfoErrorCode {
+    use std::convert::TryFrom;
+
+    let params = match params.as_mut() {
+        Some(params) => params,
+        None => return crate::fail(crate::UfoErrorCode::NullHandle, "null UfoParameters"),
+    };
+    let sizes = [header_size, element_size, element_ct, min_load_ct];
+    let converted: Result<Vec<usize>, _> = sizes.iter().map(|s| usize::try_from(*s)).collect();
+    match converted.as_deref() {
+        Ok(&[header_size, element_size, element_ct, min_load_ct]) => {
+            params.header_size = header_size;
+            params.element_size = element_size;
+            params.element_ct = element_ct;
+            params.min_load_ct = min_load_ct;
+            crate::UfoErrorCode::Ok
+        }
+        _ => crate::fail(
+            crate::UfoErrorCode::OutOfBounds,
+            format!(
+                "sizes {:?} do not fit in a {} bit size_t",
+                sizes,
+                usize::BITS
+            ),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(header_size: usize, element_size: usize, element_ct: usize) -> UfoParameters {
+        UfoParameters {
+            header_size,
+            element_size,
+            element_ct,
+            min_load_ct: 0,
+            read_only: false,
+            populate_data: std::ptr::null_mut(),
+            populate_fn: None,
+            writeback_listener_data: std::ptr::null_mut(),
+            writeback_listener: None,
+        }
+    }
+
+    #[test]
+    fn validate_accepts_ordinary_sizes() {
+        assert!(params(0, 8, 1 << 20).validate().is_ok());
+        assert!(params(4096, 1, 0).validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_zero_stride() {
+        assert!(matches!(
+            params(0, 0, 10).validate(),
+            Err(UfoConfigError::ZeroStride)
+        ));
+    }
+
+    #[test]
+    fn validate_rejects_overflow() {
+        let overflows =
+            |p: UfoParameters| matches!(p.validate(), Err(UfoConfigError::SizeOverflow));
+        assert!(overflows(params(0, 1 << 40, 1 << 40)));
+        assert!(overflows(params(usize::MAX, 1, 1)));
+        // fits in usize, but not once the header and page rounding are added
+        assert!(overflows(params(0, 1, isize::MAX as usize)));
+        assert!(overflows(params(isize::MAX as usize, 1, 0)));
+        assert!(overflows(UfoParameters {
+            min_load_ct: usize::MAX,
+            ..params(0, 8, 10)
+        }));
+    }
+
+    #[test]
+    fn set_sizes() {
+        let mut p = params(0, 0, 0);
+        assert_eq!(crate::UfoErrorCode::Ok, unsafe {
+            ufo_parameters_set_sizes(&mut p, 16, 8, 100, 4)
+        });
+        assert_eq!(
+            (16, 8, 100, 4),
+            (p.header_size, p.element_size, p.element_ct, p.min_load_ct)
+        );
+        assert_eq!(crate::UfoErrorCode::NullHandle, unsafe {
+            ufo_parameters_set_sizes(std::ptr::null_mut(), 16, 8, 100, 4)
+        });
+    }
+}
@@ -0,0 +1,238 @@
+//! A typed wrapper over the C API for Rust hosts
+
+use std::{
+    ffi::{CStr, CString},
+    marker::PhantomData,
+    panic::AssertUnwindSafe,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::populate::Populator;
+use crate::{ufo_last_error, UfoCore, UfoCoreParams, UfoErrorCode, UfoObj, UfoParameters};
+
+/// The last error recorded on this thread, or `fallback` if there was none
+fn last_error(fallback: &str) -> anyhow::Error {
+    let msg = ufo_last_error();
+    if msg.is_null() {
+        anyhow!("{}", fallback)
+    } else {
+        anyhow!("{}", unsafe { CStr::from_ptr(msg) }.to_string_lossy())
+    }
+}
+
+fn check(code: UfoErrorCode) -> Result<()> {
+    match code {
+        UfoErrorCode::Ok => Ok(()),
+        code => Err(last_error(&format!("{:?}", code))),
+    }
+}
+
+/// Types a UFO can hold. A body starts out as whatever bytes the core and
+/// the populate closure leave in it, and writeback hashes every byte, so
+/// any bit pattern must be a valid value.
+///
+/// # Safety
+///
+/// Implement only for types with no padding, no pointers or references, and
+/// no invalid bit patterns, such as the primitive integers and floats.
+pub unsafe trait UfoElement: Copy + 'static {}
+
+macro_rules! ufo_element {
+    ($($t:ty),*) => {
+        $(unsafe impl UfoElement for $t {})*
+    };
+}
+
+ufo_element!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: UfoElement, const N: usize> UfoElement for [T; N] {}
+
+pub struct UfoSystemConfig {
+    pub writeback_temp_path: String,
+    pub low_watermark: usize,
+    pub high_watermark: usize,
+}
+
+/// A UFO core. Dropping it shuts the core down, so every Ufo borrows it.
+pub struct UfoSystem {
+    core: UfoCore,
+}
+
+impl UfoSystem {
+    pub fn new(config: UfoSystemConfig) -> Result<Self> {
+        let path = CString::new(config.writeback_temp_path)?;
+        let params = UfoCoreParams {
+            writeback_temp_path: path.as_ptr(),
+            low_watermark: config.low_watermark,
+            high_watermark: config.high_watermark,
+            populate_threads: 0,
+            flags: 0,
+            max_object_bytes: 0,
+            reserved: [0; 4],
+        };
+        let core = unsafe { UfoCore::ufo_new_core_with_params(&params) };
+        if core.ufo_core_is_error() {
+            return Err(last_error("unable to create UFO core"));
+        }
+        Ok(UfoSystem { core })
+    }
+
+    /// A UFO of `ct` elements of `T` after a `header_size` byte header.
+    /// `populate(start, end, elements)` fills `elements` with elements
+    /// `start..end`. It runs on the core's populate thread and must not touch
+    /// any UFO's body. A panic in `populate` fails the populate.
+    pub fn create_ufo<T, F>(&self, ct: usize, header_size: usize, populate: F) -> Result<Ufo<'_, T>>
+    where
+        T: UfoElement,
+        F: Fn(usize, usize, &mut [T]) + Send + Sync + 'static,
+    {
+        let stride = std::mem::size_of::<T>();
+        let prototype = UfoParameters {
+            header_size,
+            element_size: stride,
+            element_ct: ct,
+            min_load_ct: 0,
+            read_only: false,
+            populate_data: std::ptr::null_mut(),
+            populate_fn: None,
+            writeback_listener_data: std::ptr::null_mut(),
+            writeback_listener: None,
+        };
+
+        let fill = move |start: usize, end: usize, buffer: *mut u8| {
+            let len = end - start;
+            if (buffer as usize).is_multiple_of(std::mem::align_of::<T>()) {
+                let elements = unsafe { std::slice::from_raw_parts_mut(buffer.cast(), len) };
+                populate(start, end, elements);
+            } else {
+                // the canary scratch buffer is only byte aligned
+                let mut elements = Vec::<T>::with_capacity(len);
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        buffer,
+                        elements.as_mut_ptr().cast(),
+                        len * stride,
+                    );
+                    elements.set_len(len);
+                }
+                populate(start, end, &mut elements);
+                unsafe {
+                    std::ptr::copy_nonoverlapping(elements.as_ptr().cast(), buffer, len * stride)
+                };
+            }
+        };
+        let populator = Populator::Closure(Box::new(move |start, end, buffer| {
+            // unwinding into the core's populate thread would take the core down
+            std::panic::catch_unwind(AssertUnwindSafe(|| fill(start, end, buffer)))
+                .map_or(-1, |()| 0)
+        }));
+
+        let obj = self.core.new_object(&prototype, populator, None);
+        if obj.ufo_is_error() {
+            return Err(last_error("unable to allocate UFO"));
+        }
+        Ok(Ufo {
+            obj,
+            ct,
+            _system: PhantomData,
+        })
+    }
+}
+
+impl Drop for UfoSystem {
+    fn drop(&mut self) {
+        self.core.ufo_core_shutdown();
+    }
+}
+
+/// A UFO holding `T`s, freed when dropped
+pub struct Ufo<'s, T> {
+    obj: UfoObj,
+    ct: usize,
+    _system: PhantomData<(&'s UfoSystem, *mut T)>,
+}
+
+impl<'s, T: UfoElement> Ufo<'s, T> {
+    /// The elements. Reading one that isn't loaded blocks while it is populated,
+    /// so this must not be used from inside a populate closure.
+    pub fn as_slice_unpopulated_hazard(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.obj.ufo_body_ptr().cast(), self.ct) }
+    }
+
+    /// As as_slice_unpopulated_hazard, but writable
+    pub fn as_mut_slice_unpopulated_hazard(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.obj.ufo_body_ptr().cast(), self.ct) }
+    }
+
+    pub fn header_ptr(&self) -> *mut u8 {
+        self.obj.ufo_header_ptr().cast()
+    }
+
+    /// Drop all loaded and written back elements so they are populated again
+    pub fn reset(&mut self) -> Result<()> {
+        check(unsafe { self.obj.ufo_reset() })
+    }
+}
+
+impl<'s, T> Drop for Ufo<'s, T> {
+    fn drop(&mut self) {
+        let obj = std::mem::replace(&mut self.obj, UfoObj::none());
+        if let Err(e) = check(obj.ufo_free()) {
+            log::warn!("unable to free UFO: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system() -> UfoSystem {
+        UfoSystem::new(UfoSystemConfig {
+            writeback_temp_path: std::env::temp_dir().to_string_lossy().into_owned(),
+            low_watermark: 16 << 20,
+            high_watermark: 64 << 20,
+        })
+        .expect("unable to create UFO system")
+    }
+
+    #[test]
+    fn typed_populate_and_access() {
+        let system = system();
+        let ct = 100_000;
+        let mut ufo = system
+            .create_ufo::<u64, _>(ct, 0, |start, end, elements| {
+                assert_eq!(end - start, elements.len());
+                for (i, e) in elements.iter_mut().enumerate() {
+                    *e = (start + i) as u64 * 3;
+                }
+            })
+            .unwrap();
+
+        let elements = ufo.as_slice_unpopulated_hazard();
+        assert_eq!(ct, elements.len());
+        assert_eq!(0, elements[0]);
+        assert_eq!(3 * 4321, elements[4321]);
+        assert_eq!(3 * (ct as u64 - 1), elements[ct - 1]);
+
+        ufo.as_mut_slice_unpopulated_hazard()[7] = 1;
+        assert_eq!(1, ufo.as_slice_unpopulated_hazard()[7]);
+        ufo.reset().unwrap();
+        assert_eq!(21, ufo.as_slice_unpopulated_hazard()[7]);
+    }
+
+    #[test]
+    fn array_elements() {
+        let system = system();
+        let ufo = system
+            .create_ufo::<[u16; 3], _>(5000, 0, |start, _, elements| {
+                for (i, e) in elements.iter_mut().enumerate() {
+                    let i = (start + i) as u16;
+                    *e = [i, i + 1, i + 2];
+                }
+            })
+            .unwrap();
+        assert_eq!([4999, 5000, 5001], ufo.as_slice_unpopulated_hazard()[4999]);
+    }
+}
@@ -0,0 +1,47 @@
+//! Cores and populate functions shared by the unit tests. Creating a core
+//! needs userfaultfd, so these tests only run where it is available.
+
+use std::ffi::CString;
+
+use crate::{UfoCore, UfoObj, UfoParameters, UfoPopulateData};
+
+pub(crate) fn test_core() -> UfoCore {
+    let path = CString::new(std::env::temp_dir().to_string_lossy().into_owned()).unwrap();
+    let core = unsafe { UfoCore::ufo_new_core(path.as_ptr(), 16 << 20, 64 << 20) };
+    assert!(!core.ufo_core_is_error(), "unable to create a UFO core");
+    core
+}
+
+/// Fills each u64 element with its index
+pub(crate) extern "C" fn index_populate(
+    _data: UfoPopulateData,
+    start: usize,
+    end: usize,
+    buffer: *mut libc::c_uchar,
+) -> i32 {
+    let elements = unsafe { std::slice::from_raw_parts_mut(buffer.cast::<u64>(), end - start) };
+    for (i, e) in elements.iter_mut().enumerate() {
+        *e = (start + i) as u64;
+    }
+    0
+}
+
+/// `element_ct` u64 elements after a `header_size` byte header, filled by index_populate
+pub(crate) fn u64_params(header_size: usize, element_ct: usize) -> UfoParameters {
+    UfoParameters {
+        header_size,
+        element_size: 8,
+        element_ct,
+        min_load_ct: 0,
+        read_only: false,
+        populate_data: std::ptr::null_mut(),
+        populate_fn: Some(index_populate),
+        writeback_listener_data: std::ptr::null_mut(),
+        writeback_listener: None,
+    }
+}
+
+/// Moves a handle to another thread, the C API allows any thread to use it
+pub(crate) struct SendObj(pub(crate) UfoObj);
+
+unsafe impl Send for SendObj {}